@@ -0,0 +1,221 @@
+// Matrix and training-step timings against the original nested-Vec matrix
+// (kept below as `Naive`): `cargo bench --bench matrix`, with
+// `--features parallel,simd` for the optional kernels.
+//
+// The "step" rows run one forward and backward pass over a batch, the
+// shape of work a replay batch does, for the default 12x64x4 network and
+// for larger ones, in f64 and in f32.
+use rusty_snake::float::Float;
+use rusty_snake::matrix::Matrix;
+use rusty_snake::nn::{Activation, ForwardPass, NeuralNetwork};
+use rusty_snake::rng;
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+// The matrix as it was: one Vec per row, a fresh result for every
+// operation and an i-j-k product
+#[derive(Clone)]
+struct Naive {
+    data: Vec<Vec<f64>>,
+    rows: usize,
+    cols: usize,
+}
+
+impl Naive {
+    fn new(rows: usize, cols: usize) -> Self {
+        Self {
+            data: vec![vec![0.0; cols]; rows],
+            rows,
+            cols,
+        }
+    }
+
+    fn from(matrix: &Matrix) -> Self {
+        let data = (0..matrix.rows()).map(|i| matrix[i].to_vec()).collect();
+        Self {
+            data,
+            rows: matrix.rows(),
+            cols: matrix.cols(),
+        }
+    }
+
+    fn multiply(&self, other: &Naive) -> Naive {
+        let mut result = Naive::new(self.rows, other.cols);
+        for i in 0..self.rows {
+            for j in 0..other.cols {
+                for k in 0..self.cols {
+                    result.data[i][j] += self.data[i][k] * other.data[k][j];
+                }
+            }
+        }
+        result
+    }
+
+    fn transpose(&self) -> Naive {
+        let mut result = Naive::new(self.cols, self.rows);
+        for i in 0..self.rows {
+            for j in 0..self.cols {
+                result.data[j][i] = self.data[i][j];
+            }
+        }
+        result
+    }
+
+    fn add_row(&self, row: &Naive) -> Naive {
+        let mut result = Naive::new(self.rows, self.cols);
+        for i in 0..self.rows {
+            for j in 0..self.cols {
+                result.data[i][j] = self.data[i][j] + row.data[0][j];
+            }
+        }
+        result
+    }
+
+    fn apply<F: Fn(f64) -> f64>(&self, func: F) -> Naive {
+        let mut result = Naive::new(self.rows, self.cols);
+        for i in 0..self.rows {
+            for j in 0..self.cols {
+                result.data[i][j] = func(self.data[i][j]);
+            }
+        }
+        result
+    }
+
+    fn hadamard(&self, other: &Naive) -> Naive {
+        let mut result = Naive::new(self.rows, self.cols);
+        for i in 0..self.rows {
+            for j in 0..self.cols {
+                result.data[i][j] = self.data[i][j] * other.data[i][j];
+            }
+        }
+        result
+    }
+}
+
+// Mean time per call, running `f` for at least half a second
+fn time<F: FnMut()>(mut f: F) -> Duration {
+    f();
+    let start = Instant::now();
+    let mut calls = 0;
+    while start.elapsed() < Duration::from_millis(500) {
+        f();
+        calls += 1;
+    }
+    start.elapsed() / calls
+}
+
+fn report(name: &str, naive: Duration, flat: Duration) {
+    println!(
+        "{:<34} {:>12.1?} {:>12.1?} {:>8.1}x",
+        name,
+        naive,
+        flat,
+        naive.as_secs_f64() / flat.as_secs_f64()
+    );
+}
+
+fn bench_multiply(rows: usize, inner: usize, cols: usize) {
+    let mut rng = rng::stream(1, 0);
+    let a = Matrix::random(rows, inner, -1.0, 1.0, &mut rng);
+    let b = Matrix::random(inner, cols, -1.0, 1.0, &mut rng);
+    let (naive_a, naive_b) = (Naive::from(&a), Naive::from(&b));
+    let mut out = Matrix::default();
+
+    let naive = time(|| {
+        black_box(naive_a.multiply(&naive_b));
+    });
+    let flat = time(|| a.multiply_into(&b, black_box(&mut out)));
+    report(
+        &format!("multiply {}x{} * {}x{}", rows, inner, inner, cols),
+        naive,
+        flat,
+    );
+}
+
+// One forward and backward pass of a ReLU network with the given layer
+// sizes over `batch` rows: the naive matrix against `NeuralNetwork` reusing
+// its buffers (which also runs the optimizer step), computing in `T`
+fn bench_step<T: Float>(sizes: &[usize], batch: usize) {
+    let mut rng = rng::stream(2, 0);
+    let mut builder = NeuralNetwork::<T>::builder().input(sizes[0]);
+    for (i, &size) in sizes[1..].iter().enumerate() {
+        let last = i == sizes.len() - 2;
+        builder = builder.dense(
+            size,
+            if last {
+                Activation::Linear
+            } else {
+                Activation::Relu
+            },
+        );
+    }
+    let mut network = builder.build(&mut rng);
+    let states: Matrix = Matrix::random(batch, sizes[0], 0.0, 1.0, &mut rng);
+    let targets: Matrix = Matrix::random(batch, sizes[sizes.len() - 1], -1.0, 1.0, &mut rng);
+    let weights = vec![1.0; batch];
+
+    let layers: Vec<(Naive, Naive)> = sizes
+        .windows(2)
+        .map(|pair| {
+            let w: Matrix = Matrix::random(pair[0], pair[1], -0.1, 0.1, &mut rng);
+            (Naive::from(&w), Naive::new(1, pair[1]))
+        })
+        .collect();
+    let naive_states = Naive::from(&states);
+    let naive_targets = Naive::from(&targets);
+    let naive = time(|| {
+        let mut inputs = vec![naive_states.clone()];
+        let mut pre_activations = Vec::new();
+        for (w, b) in &layers {
+            let z = inputs[inputs.len() - 1].multiply(w).add_row(b);
+            inputs.push(z.apply(|x| x.max(0.0)));
+            pre_activations.push(z);
+        }
+        let output = &inputs[inputs.len() - 1];
+        let mut error = Naive::new(output.rows, output.cols);
+        for i in 0..output.rows {
+            for j in 0..output.cols {
+                error.data[i][j] = output.data[i][j] - naive_targets.data[i][j];
+            }
+        }
+        for (idx, (w, _)) in layers.iter().enumerate().rev() {
+            let delta = error.hadamard(&pre_activations[idx].apply(|x| (x > 0.0) as u8 as f64));
+            black_box(inputs[idx].transpose().multiply(&delta));
+            black_box(Naive::new(1, delta.rows).apply(|_| 1.0).multiply(&delta));
+            error = delta.multiply(&w.transpose());
+        }
+        black_box(error);
+    });
+
+    let (states, targets) = (states.cast::<T>(), targets.cast::<T>());
+    let mut pass = ForwardPass::default();
+    let flat = time(|| {
+        network.forward_pass_into(&states, &mut pass);
+        network.backward_pass(&pass, &targets, &weights);
+    });
+
+    let shape: Vec<String> = sizes.iter().map(|size| size.to_string()).collect();
+    report(
+        &format!("step {} batch {} {}", shape.join("x"), batch, T::PRECISION),
+        naive,
+        flat,
+    );
+}
+
+fn main() {
+    println!(
+        "{:<34} {:>12} {:>12} {:>9}",
+        "", "nested Vec", "flat", "speed-up"
+    );
+    bench_multiply(1000, 12, 64);
+    bench_multiply(1000, 64, 4);
+    bench_multiply(256, 256, 256);
+    bench_multiply(512, 512, 512);
+    bench_step::<f64>(&[12, 64, 4], 1);
+    bench_step::<f64>(&[12, 64, 4], 1000);
+    bench_step::<f64>(&[12, 256, 256, 4], 1000);
+    bench_step::<f64>(&[64, 512, 512, 16], 256);
+    bench_step::<f32>(&[12, 64, 4], 1000);
+    bench_step::<f32>(&[12, 256, 256, 4], 1000);
+    bench_step::<f32>(&[64, 512, 512, 16], 256);
+}
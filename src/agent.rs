@@ -1,173 +1,887 @@
-use crate::nn::NeuralNetwork;
-use rand::seq::SliceRandom;
-use std::collections::VecDeque;
-use std::fmt;
-
-const MAX_MEMORY: usize = 100_000;
-const BATCH_SIZE: usize = 1000;
-
-#[derive(Hash, PartialEq, Eq, Clone)]
-pub enum Action {
-    Up,
-    Down,
-    Left,
-    Right,
+use crate::float::Float;
+use crate::matrix::{or_panic, ShapeError};
+use crate::nn::{ForwardPass, Matrix, NeuralNetwork};
+use crate::optimizer::OptimizerConfig;
+use crate::persist::{self, invalid_data};
+use crate::replay::{NStepAccumulator, PrioritizedConfig, PrioritizedReplay, Sample, Transition};
+use crate::rng::{self, SimRng};
+use rand::Rng;
+use std::io::{self, Read, Write};
+
+const AGENT_MAGIC: &[u8; 4] = b"RSAG";
+const AGENT_VERSION: u32 = 1;
+
+// Copies f64 observations into a row of `T`
+fn convert_into<T: Float>(row: &mut [T], values: &[f64]) {
+    for (x, &value) in row.iter_mut().zip(values) {
+        *x = T::from_f64(value);
+    }
+}
+
+// How the target network follows the online one
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TargetUpdate {
+    // Copy the weights over every `every` training steps
+    Hard { every: u64 },
+    // Polyak averaging after every training step
+    Soft { tau: f64 },
 }
 
-impl fmt::Display for Action {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match *self {
-            Action::Up => write!(f, "Up"),
-            Action::Down => write!(f, "Down"),
-            Action::Left => write!(f, "Left"),
-            Action::Right => write!(f, "Right"),
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AgentConfig {
+    pub target_update: TargetUpdate,
+    // Double DQN: the online network picks the next action and the target
+    // network values it, which curbs the upward bias of max-over-Q targets
+    pub double_dqn: bool,
+    // Build a dueling network (separate value and advantage streams) instead
+    // of a plain MLP
+    pub dueling: bool,
+    // Prioritized replay settings; an alpha of 0 samples uniformly
+    pub replay: PrioritizedConfig,
+    // Transitions kept in the replay memory before the oldest are dropped
+    pub memory_capacity: usize,
+    // Transitions replayed by each `train_long_memory`
+    pub batch_size: usize,
+    // Replayed transitions carry the discounted reward of this many steps
+    // and bootstrap from the state that far ahead, which carries the sparse
+    // food reward back faster than one-step targets
+    pub n_step: usize,
+    // Update rule for the trained network
+    pub optimizer: OptimizerConfig,
+}
+
+impl Default for AgentConfig {
+    fn default() -> Self {
+        Self {
+            target_update: TargetUpdate::Hard { every: 1000 },
+            double_dqn: false,
+            dueling: false,
+            replay: PrioritizedConfig::default(),
+            memory_capacity: 100_000,
+            batch_size: 1000,
+            n_step: 1,
+            optimizer: OptimizerConfig::default(),
         }
     }
 }
 
-pub struct Agent {
-    pub neural_network: NeuralNetwork,
+// Learns in the networks' precision `T`; observations, rewards and the replay
+// memory stay f64 and are converted on the way in.
+pub struct Agent<T: Float = f64> {
+    pub neural_network: NeuralNetwork<T>,
+    // Frozen copy used for the bootstrapped targets so they don't chase the
+    // network being trained
+    pub target_network: NeuralNetwork<T>,
+    pub config: AgentConfig,
     pub gamma: f64,
     pub epsilon: f64,
     pub epsilon_decay: f64,
     pub min_epsilon: f64,
-    pub memory: VecDeque<(Vec<i32>, Action, f64, Vec<i32>, bool)>,
+    // Episodes finished so far, including those before a resume
+    pub episodes: usize,
+    pub memory: PrioritizedReplay,
+    action_count: usize,
+    train_steps: u64,
+    rng: SimRng,
+    // One per source passed to `remember_from`
+    n_step: Vec<NStepAccumulator>,
+    scratch: Scratch<T>,
+}
+
+// Buffers reused by every training step, so that once they have grown to
+// the batch size training allocates nothing
+#[derive(Default)]
+struct Scratch<T: Float> {
+    batch: Sample,
+    transition: Transition,
+    states: Matrix<T>,
+    next_states: Matrix<T>,
+    // (action, reward, discount, done) per row of `states`
+    steps: Vec<(usize, f64, f64, bool)>,
+    // Importance-sampling weight per row
+    weights: Vec<f64>,
+    pass: ForwardPass<T>,
+    // Target network on `next_states`, and the online one for Double DQN
+    next_pass: ForwardPass<T>,
+    choice_pass: ForwardPass<T>,
+    // Next action per row of `next_states`
+    next_actions: Vec<usize>,
+    next_q_values: Vec<f64>,
+    targets: Matrix<T>,
+    td_errors: Vec<f64>,
 }
 
-impl Agent {
-    pub fn new() -> Self {
+impl<T: Float> Agent<T> {
+    // Sizes come from the environment, e.g.
+    // `Agent::new(env.observation_size(), env.action_count())`
+    pub fn new(observation_size: usize, action_count: usize) -> Self {
+        Self::with_seed(observation_size, action_count, rng::random_seed())
+    }
+
+    pub fn with_seed(observation_size: usize, action_count: usize, seed: u64) -> Self {
+        Self::with_config(observation_size, action_count, seed, AgentConfig::default())
+    }
+
+    pub fn with_config(
+        observation_size: usize,
+        action_count: usize,
+        seed: u64,
+        config: AgentConfig,
+    ) -> Self {
+        let mut network_rng = rng::stream(seed, rng::NETWORK_STREAM);
+        let mut neural_network = if config.dueling {
+            NeuralNetwork::dueling(observation_size, 64, action_count, &mut network_rng)
+        } else {
+            NeuralNetwork::new(observation_size, 64, action_count, &mut network_rng)
+        };
+        neural_network.set_optimizer(config.optimizer);
         Self {
-            neural_network: NeuralNetwork::new(12, 64, 4),
+            target_network: neural_network.clone(),
+            neural_network,
+            config,
             gamma: 0.9,
             epsilon: 1.0,
             epsilon_decay: 0.9999,
             min_epsilon: 0.00,
-            memory: VecDeque::with_capacity(MAX_MEMORY),
+            episodes: 0,
+            memory: PrioritizedReplay::new(config.memory_capacity, observation_size, config.replay),
+            action_count,
+            train_steps: 0,
+            rng: rng::stream(seed, rng::AGENT_STREAM),
+            n_step: Vec::new(),
+            scratch: Scratch::default(),
+        }
+    }
+
+    // Continue from previously trained weights, e.g. from `NeuralNetwork::load`
+    pub fn set_network(&mut self, network: NeuralNetwork<T>) {
+        or_panic(self.try_set_network(network))
+    }
+
+    // Fails unless `network` has as many inputs and outputs as the current
+    // one, both shapes given as inputs x outputs
+    pub fn try_set_network(&mut self, network: NeuralNetwork<T>) -> Result<(), ShapeError> {
+        let expected = (
+            self.neural_network.input_size(),
+            self.neural_network.output_size(),
+        );
+        let found = (network.input_size(), network.output_size());
+        if found != expected {
+            return Err(ShapeError {
+                operation: "network for the environment",
+                left: expected,
+                right: found,
+            });
+        }
+        self.config.dueling = network.is_dueling();
+        self.config.optimizer = network.optimizer();
+        self.target_network = network.clone();
+        self.neural_network = network;
+        Ok(())
+    }
+
+    // Exploration and learning settings plus the RNG position. The networks
+    // and replay memory are written separately; the optimizer and its state
+    // are part of the network.
+    pub fn write_state<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        persist::write_magic(w, AGENT_MAGIC, AGENT_VERSION)?;
+        persist::write_f64(w, self.gamma)?;
+        persist::write_f64(w, self.epsilon)?;
+        persist::write_f64(w, self.epsilon_decay)?;
+        persist::write_f64(w, self.min_epsilon)?;
+        persist::write_usize(w, self.action_count)?;
+        rng::write_state(w, &self.rng)?;
+        match self.config.target_update {
+            TargetUpdate::Hard { every } => {
+                persist::write_u8(w, 0)?;
+                persist::write_u64(w, every)?;
+            }
+            TargetUpdate::Soft { tau } => {
+                persist::write_u8(w, 1)?;
+                persist::write_f64(w, tau)?;
+            }
+        }
+        persist::write_u64(w, self.train_steps)?;
+        persist::write_bool(w, self.config.double_dqn)?;
+        persist::write_usize(w, self.config.batch_size)?;
+        // The steps still waiting for their n-step return
+        persist::write_usize(w, self.config.n_step)?;
+        persist::write_usize(w, self.n_step.len())?;
+        for accumulator in &self.n_step {
+            accumulator.write_to(w)?;
+        }
+        persist::write_usize(w, self.episodes)?;
+        Ok(())
+    }
+
+    // Counterpart of `write_state`. The target network starts as a copy of
+    // `network` and the memory starts empty until `read_memory` fills it.
+    pub fn read_state<R: Read>(r: &mut R, network: NeuralNetwork<T>) -> io::Result<Agent<T>> {
+        let version = persist::read_magic(r, AGENT_MAGIC)?;
+        persist::check_version("agent state", version, AGENT_VERSION)?;
+        let config = AgentConfig::default();
+        let memory =
+            PrioritizedReplay::new(config.memory_capacity, network.input_size(), config.replay);
+        let mut agent = Self {
+            target_network: network.clone(),
+            neural_network: network,
+            config,
+            gamma: persist::read_f64(r)?,
+            epsilon: persist::read_f64(r)?,
+            epsilon_decay: persist::read_f64(r)?,
+            min_epsilon: persist::read_f64(r)?,
+            episodes: 0,
+            memory,
+            action_count: persist::read_usize(r)?,
+            train_steps: 0,
+            rng: rng::read_state(r)?,
+            n_step: Vec::new(),
+            scratch: Scratch::default(),
+        };
+        agent.config.target_update = match persist::read_u8(r)? {
+            0 => TargetUpdate::Hard {
+                every: persist::read_u64(r)?,
+            },
+            1 => TargetUpdate::Soft {
+                tau: persist::read_f64(r)?,
+            },
+            other => return Err(invalid_data(format!("invalid target update {}", other))),
+        };
+        agent.train_steps = persist::read_u64(r)?;
+        agent.config.double_dqn = persist::read_bool(r)?;
+        agent.config.batch_size = persist::read_usize(r)?;
+        agent.config.n_step = persist::read_usize(r)?;
+        let sources = persist::read_usize(r)?;
+        for _ in 0..sources {
+            let accumulator = NStepAccumulator::read_from(r)?;
+            if accumulator.n() != agent.config.n_step.max(1) {
+                return Err(invalid_data(format!(
+                    "{}-step accumulator for {}-step returns",
+                    accumulator.n(),
+                    agent.config.n_step
+                )));
+            }
+            let input_size = agent.neural_network.input_size();
+            if let Some(state) = accumulator
+                .pending_states()
+                .find(|state| state.len() != input_size)
+            {
+                return Err(invalid_data(format!(
+                    "pending observation of size {} but the network takes {}",
+                    state.len(),
+                    input_size
+                )));
+            }
+            agent.n_step.push(accumulator);
+        }
+        agent.episodes = persist::read_usize(r)?;
+        // The architecture and optimizer are part of the network file and the
+        // replay settings are part of the memory file
+        agent.config.dueling = agent.neural_network.is_dueling();
+        agent.config.optimizer = agent.neural_network.optimizer();
+        if agent.action_count != agent.neural_network.output_size() {
+            return Err(invalid_data(format!(
+                "agent has {} actions but the network has {} outputs",
+                agent.action_count,
+                agent.neural_network.output_size()
+            )));
         }
+        Ok(agent)
     }
 
-    pub fn select_action(&mut self, state: &[i32; 12]) -> Action {
-        let random_float: f64 = rand::random::<f64>();
+    pub fn write_memory<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        self.memory.write_to(w)
+    }
+
+    // Replaces the replay memory (and its settings) with the one stored by
+    // `write_memory`, which must hold observations the network takes
+    pub fn read_memory<R: Read>(&mut self, r: &mut R) -> io::Result<()> {
+        let input_size = self.neural_network.input_size();
+        let mut memory = PrioritizedReplay::new(1, input_size, self.config.replay);
+        memory.read_from(r)?;
+        let observation_size = memory.buffer().observation_size();
+        if observation_size != input_size {
+            return Err(invalid_data(format!(
+                "replay memory holds observations of size {} but the network takes {}",
+                observation_size, input_size
+            )));
+        }
+        if let Some(t) = memory
+            .buffer()
+            .iter()
+            .find(|t| t.action >= self.action_count)
+        {
+            return Err(invalid_data(format!(
+                "replay memory holds action {} but there are only {}",
+                t.action, self.action_count
+            )));
+        }
+        self.memory = memory;
+        self.config.replay = self.memory.config;
+        self.config.memory_capacity = self.memory.capacity();
+        Ok(())
+    }
+
+    // Copy of the policy for acting elsewhere (e.g. a self-play worker), with
+    // the same weights and exploration settings but no replay memory and its
+    // own exploration seed.
+    pub fn actor(&self, seed: u64) -> Agent<T> {
+        Agent {
+            neural_network: self.neural_network.clone(),
+            target_network: self.target_network.clone(),
+            config: self.config,
+            gamma: self.gamma,
+            epsilon: self.epsilon,
+            epsilon_decay: self.epsilon_decay,
+            min_epsilon: self.min_epsilon,
+            episodes: self.episodes,
+            memory: PrioritizedReplay::new(1, self.neural_network.input_size(), self.config.replay),
+            action_count: self.action_count,
+            train_steps: self.train_steps,
+            rng: rng::stream(seed, rng::AGENT_STREAM),
+            n_step: Vec::new(),
+            scratch: Scratch::default(),
+        }
+    }
+
+    pub fn select_action(&mut self, state: &[f64]) -> usize {
+        let random_float: f64 = self.rng.gen::<f64>();
         self.epsilon = (self.epsilon * self.epsilon_decay).max(self.min_epsilon);
         //println!("Epsilon: {}, Float: {}", self.epsilon, random_float);
-        let action = if random_float < self.epsilon {
+        if random_float < self.epsilon {
             //println!("random");
-            vec![Action::Up, Action::Down, Action::Left, Action::Right]
-                .choose(&mut rand::thread_rng())
-                .unwrap()
-                .clone()
+            self.rng.gen_range(0..self.action_count)
         } else {
             //println!("not random");
+            let state: Vec<T> = state.iter().map(|&x| T::from_f64(x)).collect();
+            let q_values = self
+                .neural_network
+                .forward_batch(&Matrix::from_array_to_row(&state));
+            q_values.argmax()[0]
+        }
+    }
 
-            let q_values = self.neural_network.forward(&state);
-            let max_q_value_action = q_values
-                .iter()
-                .enumerate()
-                .max_by(|a, b| a.1.partial_cmp(b.1).unwrap())
-                .unwrap()
-                .0;
-            match max_q_value_action {
-                0 => Action::Up,
-                1 => Action::Down,
-                2 => Action::Left,
-                3 => Action::Right,
-                _ => panic!("Unexpected action index"),
-            }
-        };
-        // println!("Action Taken :{}", action);
-        action
+    // One action per row, e.g. for every environment of a `VecEnv`
+    pub fn select_actions(&mut self, states: &[Vec<f64>]) -> Vec<usize> {
+        states
+            .iter()
+            .map(|state| self.select_action(state))
+            .collect()
     }
 
     pub fn remember(
         &mut self,
-        state: &[i32; 12],
-        action: Action,
+        state: &[f64],
+        action: usize,
         reward: f64,
-        next_state: &[i32; 12],
+        next_state: &[f64],
         done: bool,
     ) {
-        // If the memory is full, remove the oldest entry.
-        if self.memory.len() == MAX_MEMORY {
-            self.memory.pop_front();
-        }
-    
-        // Create the data tuple.
-        let data = (state.to_vec(), action, reward, next_state.to_vec(), done);
-    
-        // If the reward is positive, push the data 100 times.
-        if reward > 0.0 {
-            for _ in 0..10 {
-                // Check for overflow in each iteration
-                if self.memory.len() == MAX_MEMORY {
-                    self.memory.pop_front();
-                }
-                self.memory.push_back(data.clone());
-            }
-        } else {
-            self.memory.push_back(data);
+        self.remember_from(0, state, action, reward, next_state, done);
+    }
+
+    // Like `remember` for one of several interleaved streams of steps (an
+    // environment of a `VecEnv`, a self-play worker), each of which builds
+    // its own n-step transitions
+    pub fn remember_from(
+        &mut self,
+        source: usize,
+        state: &[f64],
+        action: usize,
+        reward: f64,
+        next_state: &[f64],
+        done: bool,
+    ) {
+        if self.n_step.len() <= source {
+            let (n, gamma) = (self.config.n_step.max(1), self.gamma);
+            self.n_step
+                .resize_with(source + 1, || NStepAccumulator::new(n, gamma));
         }
-    }    
+        // Once full, the oldest entry in the memory is overwritten
+        self.n_step[source].push(state, action, reward, next_state, done, &mut self.memory);
+    }
 
+    // Replays a batch drawn by priority, scaling each update by its
+    // importance-sampling weight, then refreshes the priorities with the new
+    // TD errors
     pub fn train_long_memory(&mut self) {
-        let sample_size = BATCH_SIZE.min(self.memory.len());
-        let mini_sample: Vec<_> = self
-            .memory
-            .make_contiguous()
-            .choose_multiple(&mut rand::thread_rng(), sample_size)
-            .cloned()
-            .collect();
-        let states: Vec<_> = mini_sample.iter().map(|item| &item.0).collect();
-        let actions: Vec<_> = mini_sample.iter().map(|item| &item.1).collect();
-        let rewards: Vec<_> = mini_sample.iter().map(|item| item.2).collect();
-        let next_states: Vec<_> = mini_sample.iter().map(|item| &item.3).collect();
-        let dones: Vec<_> = mini_sample.iter().map(|item| item.4).collect();
-
-        // Now the `train` function will need to be updated to accept batches instead of individual items.
-        for i in 0..sample_size {
-            let state_array: [i32; 12] = states[i].clone().try_into().expect("Wrong length");
-            let next_state_array: [i32; 12] =
-                next_states[i].clone().try_into().expect("Wrong length");
-            self.train(
-                &state_array,
-                actions[i].clone(),
-                rewards[i],
-                &next_state_array,
-                dones[i],
-            );
+        if self.memory.is_empty() {
+            return;
+        }
+        let batch_size = self.config.batch_size.min(self.memory.len());
+        let observation_size = self.neural_network.input_size();
+        let scratch = &mut self.scratch;
+        self.memory
+            .sample_into(batch_size, &mut self.rng, &mut scratch.batch);
+
+        scratch.states.resize(batch_size, observation_size);
+        scratch.next_states.resize(batch_size, observation_size);
+        scratch.steps.clear();
+        for (row, &slot) in scratch.batch.indices.iter().enumerate() {
+            let transition = &mut scratch.transition;
+            self.memory.read_into(slot, transition);
+            convert_into(&mut scratch.states[row], &transition.state);
+            convert_into(&mut scratch.next_states[row], &transition.next_state);
+            scratch.steps.push((
+                transition.action,
+                transition.reward,
+                transition.discount,
+                transition.done,
+            ));
+        }
+        scratch.weights.clear();
+        scratch.weights.extend_from_slice(&scratch.batch.weights);
+
+        // One step on the gradient averaged over the whole batch
+        self.fit();
+
+        let scratch = &self.scratch;
+        for (&slot, &td_error) in scratch.batch.indices.iter().zip(&scratch.td_errors) {
+            self.memory.update_priority(slot, td_error);
         }
     }
 
     pub fn train(
         &mut self,
-        state: &[i32; 12],
-        action: Action,
+        state: &[f64],
+        action: usize,
         reward: f64,
-        next_state: &[i32; 12],
+        next_state: &[f64],
         done: bool,
     ) {
+        let scratch = &mut self.scratch;
+        scratch.states.resize(1, state.len());
+        convert_into(&mut scratch.states[0], state);
+        scratch.next_states.resize(1, next_state.len());
+        convert_into(&mut scratch.next_states[0], next_state);
+        scratch.steps.clear();
+        scratch.steps.push((action, reward, self.gamma, done));
+        scratch.weights.clear();
+        scratch.weights.push(1.0);
+        self.fit();
+    }
 
-        let current_q_values = self.neural_network.forward(&state);
-        let next_q_values = self.neural_network.forward(&next_state);
-        let mut target_q_values = current_q_values.clone();
-        let next_max_q_value = *next_q_values
-            .iter()
-            .max_by(|a, b| a.partial_cmp(b).unwrap())
+    // One gradient step towards the bootstrapped targets of the transitions
+    // in the scratch buffers (`states`, `steps`, `next_states`, weighted by
+    // `weights`), leaving their TD errors in `td_errors`; then the target
+    // network follows
+    fn fit(&mut self) {
+        let scratch = &mut self.scratch;
+        self.neural_network
+            .forward_pass_into(&scratch.states, &mut scratch.pass);
+        Self::next_q_values(
+            &self.neural_network,
+            &self.target_network,
+            self.config.double_dqn,
+            scratch,
+        );
+
+        scratch.targets.copy_from(scratch.pass.output());
+        scratch.td_errors.clear();
+        for (row, &(action, reward, discount, done)) in scratch.steps.iter().enumerate() {
+            let target = if done {
+                reward
+            } else {
+                reward + discount * scratch.next_q_values[row]
+            };
+            scratch
+                .td_errors
+                .push(target - scratch.targets[row][action].to_f64());
+            scratch.targets[row][action] = T::from_f64(target);
+        }
+
+        self.neural_network
+            .backward_pass(&scratch.pass, &scratch.targets, &scratch.weights);
+        self.train_steps += 1;
+        self.update_target_network();
+    }
+
+    // Bootstrapped value of each state in `scratch.next_states`, into
+    // `scratch.next_q_values`
+    fn next_q_values(
+        online: &NeuralNetwork<T>,
+        target: &NeuralNetwork<T>,
+        double_dqn: bool,
+        scratch: &mut Scratch<T>,
+    ) {
+        target.forward_pass_into(&scratch.next_states, &mut scratch.next_pass);
+        let target_q_values = scratch.next_pass.output();
+        // Double DQN: the online network picks, the target one values
+        let choice = if double_dqn {
+            online.forward_pass_into(&scratch.next_states, &mut scratch.choice_pass);
+            scratch.choice_pass.output()
+        } else {
+            target_q_values
+        };
+        choice.argmax_into(&mut scratch.next_actions);
+        scratch.next_q_values.clear();
+        for (row, &action) in scratch.next_actions.iter().enumerate() {
+            scratch
+                .next_q_values
+                .push(target_q_values[row][action].to_f64());
+        }
+    }
+
+    fn update_target_network(&mut self) {
+        match self.config.target_update {
+            TargetUpdate::Hard { every } => {
+                if self.train_steps.is_multiple_of(every.max(1)) {
+                    self.target_network.copy_from(&self.neural_network);
+                }
+            }
+            TargetUpdate::Soft { tau } => {
+                self.target_network.soft_update(&self.neural_network, tau)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::env::Environment;
+    use crate::game::{BoardConfig, Game};
+
+    fn small_config() -> AgentConfig {
+        AgentConfig {
+            memory_capacity: 500,
+            batch_size: 16,
+            ..AgentConfig::default()
+        }
+    }
+
+    // Plays and trains for `steps` steps; returns every (action, reward,
+    // done) along the way and the serialized weights at the end
+    fn play(seed: u64, steps: usize) -> (Vec<(usize, f64, bool)>, Vec<u8>) {
+        let mut game = Game::with_seed(BoardConfig::new(10, 10), seed);
+        let mut agent: Agent = Agent::with_config(
+            game.observation_size(),
+            game.action_count(),
+            seed,
+            small_config(),
+        );
+        // Leave exploration early so the network's choices matter too
+        agent.epsilon_decay = 0.99;
+
+        let mut episode = Vec::new();
+        let mut state = Environment::reset(&mut game);
+        for _ in 0..steps {
+            let action = agent.select_action(&state);
+            let outcome = Environment::step(&mut game, action);
+            agent.remember(
+                &state,
+                action,
+                outcome.reward,
+                &outcome.observation,
+                outcome.done,
+            );
+            episode.push((action, outcome.reward, outcome.done));
+            state = if outcome.done {
+                agent.train_long_memory();
+                Environment::reset(&mut game)
+            } else {
+                outcome.observation
+            };
+        }
+        let mut weights = Vec::new();
+        agent.neural_network.write_to(&mut weights).unwrap();
+        (episode, weights)
+    }
+
+    #[test]
+    fn same_seed_plays_and_learns_the_same() {
+        let (episode, weights) = play(7, 300);
+        assert!(episode.iter().any(|&(_, _, done)| done));
+        assert_eq!(play(7, 300), (episode.clone(), weights.clone()));
+        assert_ne!(play(8, 300), (episode, weights));
+    }
+
+    #[test]
+    fn saved_agents_continue_exactly() {
+        let mut agent: Agent = Agent::with_config(12, 4, 3, small_config());
+        agent.config.n_step = 3;
+        agent.epsilon_decay = 0.9;
+        let observation = |i: usize| (0..12).map(|j| ((i + j) % 2) as f64).collect::<Vec<_>>();
+        for i in 0..40 {
+            let action = agent.select_action(&observation(i));
+            agent.remember_from(
+                i % 2,
+                &observation(i),
+                action,
+                0.5,
+                &observation(i + 1),
+                i % 7 == 6,
+            );
+        }
+        agent.train_long_memory();
+        agent.episodes = 6;
+
+        let mut state = Vec::new();
+        agent.write_state(&mut state).unwrap();
+        let mut memory = Vec::new();
+        agent.write_memory(&mut memory).unwrap();
+        let mut loaded =
+            Agent::read_state(&mut state.as_slice(), agent.neural_network.clone()).unwrap();
+        loaded.target_network.copy_from(&agent.target_network);
+        loaded.read_memory(&mut memory.as_slice()).unwrap();
+
+        let mut again = Vec::new();
+        loaded.write_state(&mut again).unwrap();
+        assert_eq!(again, state);
+        again.clear();
+        loaded.write_memory(&mut again).unwrap();
+        assert_eq!(again, memory);
+        assert_eq!((loaded.episodes, loaded.config), (6, agent.config));
+
+        // The pending n-step returns, the RNG and the priorities all carry on
+        for agent in [&mut agent, &mut loaded] {
+            for i in 40..60 {
+                let action = agent.select_action(&observation(i));
+                agent.remember_from(
+                    i % 2,
+                    &observation(i),
+                    action,
+                    -1.0,
+                    &observation(i + 1),
+                    false,
+                );
+            }
+            agent.train_long_memory();
+        }
+        let weights = |agent: &Agent| {
+            let mut bytes = Vec::new();
+            agent.neural_network.write_to(&mut bytes).unwrap();
+            bytes
+        };
+        assert_eq!(weights(&loaded), weights(&agent));
+    }
+
+    #[test]
+    fn memories_for_other_observation_sizes_are_rejected() {
+        let other: Agent = Agent::with_config(10, 4, 1, small_config());
+        let mut memory = Vec::new();
+        other.write_memory(&mut memory).unwrap();
+
+        let mut agent: Agent = Agent::with_config(12, 4, 1, small_config());
+        let error = agent.read_memory(&mut memory.as_slice()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(agent.memory.buffer().observation_size(), 12);
+    }
+
+    #[test]
+    fn memories_with_unknown_actions_are_rejected() {
+        let mut other: Agent = Agent::with_config(12, 5, 1, small_config());
+        other.remember(&[0.0; 12], 4, 1.0, &[1.0; 12], false);
+        let mut memory = Vec::new();
+        other.write_memory(&mut memory).unwrap();
+
+        let mut agent: Agent = Agent::with_config(12, 4, 1, small_config());
+        let error = agent.read_memory(&mut memory.as_slice()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(agent.memory.is_empty());
+    }
+
+    #[test]
+    fn pending_n_step_returns_must_fit_the_agent() {
+        let config = AgentConfig {
+            n_step: 3,
+            ..small_config()
+        };
+        let mut agent: Agent = Agent::with_config(12, 4, 1, config);
+        agent.remember(&[0.0; 12], 1, 1.0, &[1.0; 12], false);
+        let mut state = Vec::new();
+        agent.write_state(&mut state).unwrap();
+        let network = agent.neural_network.clone();
+        assert!(Agent::read_state(&mut state.as_slice(), network.clone()).is_ok());
+
+        // A pending step from an environment the network does not take
+        let mut rng = rng::stream(2, 0);
+        let other: NeuralNetwork = NeuralNetwork::new(10, 16, 4, &mut rng);
+        let error = Agent::read_state(&mut state.as_slice(), other)
+            .err()
             .unwrap();
-        let action_idx = match action {
-            Action::Up => 0,
-            Action::Down => 1,
-            Action::Left => 2,
-            Action::Right => 3,
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+        // An accumulator for returns over another number of steps
+        agent.config.n_step = 2;
+        state.clear();
+        agent.write_state(&mut state).unwrap();
+        let error = Agent::read_state(&mut state.as_slice(), network)
+            .err()
+            .unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn each_source_builds_its_own_n_step_returns() {
+        let config = AgentConfig {
+            n_step: 2,
+            ..small_config()
         };
-        let q_new = if done {
-            reward
-        } else {
-            reward + self.gamma * next_max_q_value
+        let mut agent: Agent = Agent::with_config(2, 4, 1, config);
+        agent.gamma = 0.5;
+        // Two sources taking turns, one earning 1 per step and the other 10
+        for step in 0..3 {
+            let i = step as f64;
+            agent.remember_from(0, &[0.0, i], 0, 1.0, &[0.0, i + 1.0], false);
+            agent.remember_from(1, &[1.0, i], 1, 10.0, &[1.0, i + 1.0], false);
+        }
+        let stored: Vec<_> = agent
+            .memory
+            .buffer()
+            .iter()
+            .map(|t| (t.state, t.action, t.reward, t.next_state))
+            .collect();
+        assert_eq!(
+            stored,
+            [
+                (vec![0.0, 0.0], 0, 1.5, vec![0.0, 2.0]),
+                (vec![1.0, 0.0], 1, 15.0, vec![1.0, 2.0]),
+                (vec![0.0, 1.0], 0, 1.5, vec![0.0, 3.0]),
+                (vec![1.0, 1.0], 1, 15.0, vec![1.0, 3.0]),
+            ]
+        );
+    }
+
+    // A few observations to read the networks' Q-values on
+    fn probe_states() -> Matrix {
+        let mut states = Matrix::new(3, 12);
+        for (i, value) in states.as_mut_slice().iter_mut().enumerate() {
+            *value = ((i * 7) % 5) as f64 - 2.0;
+        }
+        states
+    }
+
+    // One online training step on a fixed transition
+    fn train_once(agent: &mut Agent) {
+        let state: Vec<f64> = (0..12).map(|i| (i % 3) as f64).collect();
+        let next_state: Vec<f64> = (0..12).map(|i| (i % 2) as f64).collect();
+        agent.train(&state, 1, 1.0, &next_state, false);
+    }
+
+    #[test]
+    fn hard_updates_copy_the_online_network_every_few_steps() {
+        let config = AgentConfig {
+            target_update: TargetUpdate::Hard { every: 3 },
+            ..small_config()
         };
+        let mut agent: Agent = Agent::with_config(12, 4, 1, config);
+        let states = probe_states();
+        let initial = agent.target_network.forward_batch(&states);
 
-        target_q_values[action_idx] = q_new;
+        for _ in 0..2 {
+            train_once(&mut agent);
+            assert_eq!(agent.target_network.forward_batch(&states), initial);
+            assert_ne!(agent.neural_network.forward_batch(&states), initial);
+        }
+        train_once(&mut agent);
+        let copied = agent.target_network.forward_batch(&states);
+        assert_eq!(copied, agent.neural_network.forward_batch(&states));
 
-        self.neural_network
-            .backward(&state, target_q_values, current_q_values);
+        train_once(&mut agent);
+        assert_eq!(agent.target_network.forward_batch(&states), copied);
+    }
+
+    #[test]
+    fn soft_updates_move_the_target_network_by_tau() {
+        let config = AgentConfig {
+            target_update: TargetUpdate::Soft { tau: 0.25 },
+            ..small_config()
+        };
+        let mut agent: Agent = Agent::with_config(12, 4, 1, config);
+        let states = probe_states();
+        for _ in 0..3 {
+            let mut expected = agent.target_network.clone();
+            train_once(&mut agent);
+            expected.soft_update(&agent.neural_network, 0.25);
+            assert_eq!(
+                agent.target_network.forward_batch(&states),
+                expected.forward_batch(&states)
+            );
+            assert_ne!(
+                agent.target_network.forward_batch(&states),
+                agent.neural_network.forward_batch(&states)
+            );
+        }
+    }
+
+    #[test]
+    fn bootstrapped_values_come_from_the_target_network() {
+        let mut agent: Agent = Agent::with_seed(12, 4, 1);
+        agent.target_network = Agent::with_seed(12, 4, 2).neural_network;
+        let states = probe_states();
+        let best = |q_values: &Matrix| -> Vec<f64> {
+            (0..q_values.rows())
+                .map(|row| q_values[row].iter().copied().fold(f64::MIN, f64::max))
+                .collect()
+        };
+
+        let mut scratch = Scratch {
+            next_states: states.clone(),
+            ..Scratch::default()
+        };
+        Agent::next_q_values(
+            &agent.neural_network,
+            &agent.target_network,
+            false,
+            &mut scratch,
+        );
+        let target = agent.target_network.forward_batch(&states);
+        assert_eq!(scratch.next_q_values, best(&target));
+        assert_ne!(
+            scratch.next_q_values,
+            best(&agent.neural_network.forward_batch(&states))
+        );
+    }
+
+    #[test]
+    fn double_dqn_values_the_online_networks_choice_with_the_target_network() {
+        let mut agent: Agent = Agent::with_seed(12, 4, 1);
+        agent.target_network = Agent::with_seed(12, 4, 2).neural_network;
+        let states = probe_states();
+        let online = agent.neural_network.forward_batch(&states);
+        let target = agent.target_network.forward_batch(&states);
+        let (choices, greedy) = (online.argmax(), target.argmax());
+        assert!(choices.iter().zip(&greedy).any(|(a, b)| a != b));
+
+        let bootstrap = |double_dqn| {
+            let mut scratch = Scratch {
+                next_states: states.clone(),
+                ..Scratch::default()
+            };
+            Agent::next_q_values(
+                &agent.neural_network,
+                &agent.target_network,
+                double_dqn,
+                &mut scratch,
+            );
+            scratch.next_q_values
+        };
+        let (double, vanilla) = (bootstrap(true), bootstrap(false));
+        for row in 0..states.rows() {
+            assert_eq!(double[row], target[row][choices[row]]);
+            assert_eq!(vanilla[row], target[row][greedy[row]]);
+            if choices[row] != greedy[row] {
+                assert!(double[row] < vanilla[row]);
+            }
+        }
+    }
+
+    #[test]
+    fn networks_for_other_environments_are_refused() {
+        let mut agent: Agent = Agent::with_seed(12, 4, 1);
+        let mut rng = rng::stream(2, 0);
+        let error = agent
+            .try_set_network(NeuralNetwork::new(11, 16, 4, &mut rng))
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "shape mismatch in network for the environment: 12x4 and 11x4"
+        );
+        assert!(!agent.config.dueling);
+
+        let dueling = NeuralNetwork::dueling(12, 16, 4, &mut rng);
+        assert!(agent.try_set_network(dueling).is_ok());
+        assert!(agent.config.dueling);
     }
 }
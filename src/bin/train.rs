@@ -0,0 +1,345 @@
+// Headless trainer: runs the same game/agent loop as the window without
+// drawing anything, as fast as the CPU allows.
+//
+// Usage: train [--games N] [--envs N] [--workers N] [--seed N] [--board WxH]
+//              [--load PATH] [--save PATH]
+//              [--checkpoint DIR] [--autosave N] [--resume DIR]
+//              [--target-sync N | --target-tau T] [--double-dqn] [--dueling]
+//              [--replay-alpha A] [--replay-beta B] [--beta-annealing N]
+//              [--n-step N] [--optimizer sgd|momentum|rmsprop|adam] [--learning-rate R]
+//              [--precision f32|f64]
+//
+// With `--workers` the games are played on that many threads while this
+// thread does the learning; otherwise `--envs` snakes are stepped in lockstep.
+// `--load` starts from saved weights and `--save` writes them out at the end.
+//
+// `--checkpoint` writes the whole training state (weights, replay memory,
+// epsilon, RNGs and games) every `--autosave` games and at the end, and
+// `--resume` continues from one; `--games` counts the games played before the
+// interruption too. With `--workers` the games live on the worker threads and
+// are not part of the checkpoint, so only the agent is restored (including its
+// exploration rate and the number of games played).
+//
+// The target network is copied from the trained one every `--target-sync`
+// training steps (default 1000), or follows it by Polyak averaging with
+// `--target-tau`. `--double-dqn` lets the trained network choose the next
+// action while the target network values it. `--dueling` starts a new agent
+// with a dueling network (a resumed or loaded one keeps its architecture).
+//
+// Replay samples are drawn in proportion to |TD error|^`--replay-alpha`
+// (0 is uniform) and weighted by importance sampling with an exponent that
+// rises from `--replay-beta` to 1 over `--beta-annealing` batches. Like
+// `--dueling` these only apply to a new agent; a resumed one keeps its own.
+// So does `--n-step`, which replays the discounted reward of N steps and
+// bootstraps from the state N steps later (default 1).
+//
+// `--optimizer` picks the update rule (default sgd) and `--learning-rate` its
+// step size (default 0.001). Both also apply to a resumed or loaded network;
+// switching to another kind of optimizer restarts its state.
+//
+// `--precision f32` trains the networks in single precision, which is faster
+// and halves their memory; the default is f64. A resumed run or a loaded
+// model keeps the precision it was saved in unless `--precision` converts it.
+use rusty_snake::agent::{Agent, AgentConfig, TargetUpdate};
+use rusty_snake::checkpoint;
+use rusty_snake::env::Environment;
+use rusty_snake::float::{Float, Precision};
+use rusty_snake::game::{BoardConfig, Game, GameEvent};
+use rusty_snake::nn::{self, NeuralNetwork};
+use rusty_snake::optimizer::OptimizerConfig;
+use rusty_snake::parallel::{self, EpisodeStats, ParallelConfig};
+use rusty_snake::replay::PrioritizedConfig;
+use rusty_snake::rng;
+use rusty_snake::vec_env::{self, VecEnv};
+use std::fs::File;
+
+struct Options {
+    games: usize,
+    envs: usize,
+    workers: usize,
+    seed: u64,
+    board: BoardConfig,
+    load: Option<String>,
+    save: Option<String>,
+    checkpoint: Option<String>,
+    autosave: usize,
+    resume: Option<String>,
+    target_update: Option<TargetUpdate>,
+    double_dqn: bool,
+    dueling: bool,
+    replay: PrioritizedConfig,
+    n_step: usize,
+    optimizer: Option<OptimizerConfig>,
+    learning_rate: Option<f64>,
+    precision: Option<Precision>,
+}
+
+impl Options {
+    fn from_args() -> Self {
+        let mut options = Options {
+            games: 1000,
+            envs: 1,
+            workers: 0,
+            seed: rng::random_seed(),
+            board: BoardConfig::default(),
+            load: None,
+            save: None,
+            checkpoint: None,
+            autosave: 100,
+            resume: None,
+            target_update: None,
+            double_dqn: false,
+            dueling: false,
+            replay: PrioritizedConfig::default(),
+            n_step: 1,
+            optimizer: None,
+            learning_rate: None,
+            precision: None,
+        };
+        let mut args = std::env::args().skip(1);
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next()
+                    .unwrap_or_else(|| panic!("missing value for {}", arg))
+            };
+            match arg.as_str() {
+                "--games" => options.games = value().parse().expect("--games expects a number"),
+                "--envs" => options.envs = value().parse().expect("--envs expects a number"),
+                "--board" => options.board = value().parse().unwrap_or_else(|e| panic!("{}", e)),
+                "--load" => options.load = Some(value()),
+                "--checkpoint" => options.checkpoint = Some(value()),
+                "--autosave" => {
+                    options.autosave = value().parse().expect("--autosave expects a number")
+                }
+                "--resume" => options.resume = Some(value()),
+                "--double-dqn" => options.double_dqn = true,
+                "--dueling" => options.dueling = true,
+                "--replay-alpha" => {
+                    options.replay.alpha = value().parse().expect("--replay-alpha expects a number")
+                }
+                "--replay-beta" => {
+                    options.replay.beta = value().parse().expect("--replay-beta expects a number")
+                }
+                "--beta-annealing" => {
+                    options.replay.beta_annealing_steps =
+                        value().parse().expect("--beta-annealing expects a number")
+                }
+                "--n-step" => options.n_step = value().parse().expect("--n-step expects a number"),
+                "--optimizer" => {
+                    options.optimizer = Some(value().parse().unwrap_or_else(|e| panic!("{}", e)))
+                }
+                "--learning-rate" => {
+                    let rate = value().parse().expect("--learning-rate expects a number");
+                    options.learning_rate = Some(rate);
+                }
+                "--precision" => {
+                    options.precision = Some(value().parse().unwrap_or_else(|e| panic!("{}", e)))
+                }
+                "--target-sync" => {
+                    let every = value().parse().expect("--target-sync expects a number");
+                    options.target_update = Some(TargetUpdate::Hard { every });
+                }
+                "--target-tau" => {
+                    let tau = value().parse().expect("--target-tau expects a number");
+                    options.target_update = Some(TargetUpdate::Soft { tau });
+                }
+                "--save" => options.save = Some(value()),
+                "--seed" => options.seed = value().parse().expect("--seed expects a number"),
+                "--workers" => {
+                    options.workers = value().parse().expect("--workers expects a number")
+                }
+                _ => panic!("unknown argument: {}", arg),
+            }
+        }
+        options
+    }
+}
+
+fn main() {
+    let options = Options::from_args();
+    println!("Seed: {}", options.seed);
+
+    let precision = options
+        .precision
+        .unwrap_or_else(|| saved_precision(&options));
+    println!("Precision: {}", precision);
+    match precision {
+        Precision::F32 => run::<f32>(&options),
+        Precision::F64 => run::<f64>(&options),
+    }
+}
+
+// Precision of the checkpoint or model the run continues from, f64 for a
+// fresh one
+fn saved_precision(options: &Options) -> Precision {
+    if let Some(dir) = &options.resume {
+        checkpoint::precision(dir)
+            .unwrap_or_else(|err| panic!("failed to resume from {}: {}", dir, err))
+    } else if let Some(path) = &options.load {
+        File::open(path)
+            .and_then(|mut file| nn::read_precision(&mut file))
+            .unwrap_or_else(|err| panic!("failed to load {}: {}", path, err))
+    } else {
+        Precision::F64
+    }
+}
+
+fn run<T: Float>(options: &Options) {
+    let (mut agent, games): (Agent<T>, _) = match &options.resume {
+        Some(dir) => {
+            let (agent, games) = checkpoint::load(dir)
+                .unwrap_or_else(|err| panic!("failed to resume from {}: {}", dir, err));
+            println!("Resumed from {} ({} games)", dir, games.len());
+            (agent, games)
+        }
+        None => {
+            let game = Game::with_seed(options.board, options.seed);
+            let agent = Agent::with_config(
+                game.observation_size(),
+                game.action_count(),
+                options.seed,
+                AgentConfig {
+                    dueling: options.dueling,
+                    replay: options.replay,
+                    n_step: options.n_step.max(1),
+                    ..AgentConfig::default()
+                },
+            );
+            (agent, Vec::new())
+        }
+    };
+    if let Some(target_update) = options.target_update {
+        agent.config.target_update = target_update;
+    }
+    if options.double_dqn {
+        agent.config.double_dqn = true;
+    }
+    if let Some(path) = &options.load {
+        let network = NeuralNetwork::load(path)
+            .unwrap_or_else(|err| panic!("failed to load {}: {}", path, err));
+        agent.set_network(network);
+    }
+    if options.optimizer.is_some() || options.learning_rate.is_some() {
+        let mut optimizer = options.optimizer.unwrap_or(agent.config.optimizer);
+        if let Some(rate) = options.learning_rate {
+            optimizer = optimizer.with_learning_rate(rate);
+        }
+        agent.config.optimizer = optimizer;
+        agent.neural_network.set_optimizer(optimizer);
+    }
+
+    let agent = if options.workers > 0 {
+        train_parallel(options, agent)
+    } else {
+        train_lockstep(options, agent, games)
+    };
+
+    if let Some(path) = &options.save {
+        agent
+            .neural_network
+            .save(path)
+            .unwrap_or_else(|err| panic!("failed to save {}: {}", path, err));
+        println!("Saved model to {}", path);
+    }
+}
+
+fn train_parallel<T: Float>(options: &Options, agent: Agent<T>) -> Agent<T> {
+    let (seed, board) = (options.seed, options.board);
+    let config = ParallelConfig {
+        workers: options.workers,
+        episodes: options.games,
+        seed,
+        ..ParallelConfig::default()
+    };
+
+    let mut progress = Progress::new(options, &agent, &[]);
+    let agent = parallel::train(
+        &config,
+        agent,
+        move |worker| Game::with_seed(board, seed.wrapping_add(worker as u64)),
+        |stats, agent| progress.episode_done(stats, agent, &[]),
+    );
+    save_checkpoint(options, &agent, &[]);
+    agent
+}
+
+fn train_lockstep<T: Float>(options: &Options, mut agent: Agent<T>, games: Vec<Game>) -> Agent<T> {
+    let mut envs = if games.is_empty() {
+        let games = (0..options.envs)
+            .map(|i| Game::with_seed(options.board, options.seed.wrapping_add(i as u64)))
+            .collect();
+        VecEnv::new(games)
+    } else {
+        VecEnv::resume(games)
+    };
+
+    let mut progress = Progress::new(options, &agent, envs.envs());
+    vec_env::train(
+        &mut agent,
+        &mut envs,
+        options.games,
+        |stats, agent, games| progress.episode_done(stats, agent, games),
+    );
+    save_checkpoint(options, &agent, envs.envs());
+    agent
+}
+
+// Logs finished games and writes a checkpoint every `--autosave` of them, the
+// same way in either training mode
+struct Progress<'a> {
+    options: &'a Options,
+    high_score: usize,
+    last_autosave: usize,
+    // Food already eaten in the games a checkpoint was resumed mid-episode
+    carried_scores: Vec<usize>,
+}
+
+impl<'a> Progress<'a> {
+    fn new<T: Float>(options: &'a Options, agent: &Agent<T>, games: &[Game]) -> Self {
+        Self {
+            options,
+            high_score: games.iter().map(|game| game.high_score).max().unwrap_or(0) as usize,
+            last_autosave: agent.episodes / options.autosave.max(1),
+            carried_scores: games.iter().map(|game| game.score as usize).collect(),
+        }
+    }
+
+    fn episode_done<T: Float>(
+        &mut self,
+        stats: &EpisodeStats<GameEvent>,
+        agent: &Agent<T>,
+        games: &[Game],
+    ) {
+        let carried = self
+            .carried_scores
+            .get_mut(stats.worker)
+            .map_or(0, std::mem::take);
+        let score = stats.count(&GameEvent::AteFood) + carried;
+        self.high_score = self.high_score.max(score);
+        if self.options.workers > 0 {
+            println!(
+                "Game {} (worker {}) Score: {} High score: {}",
+                stats.episode, stats.worker, score, self.high_score
+            );
+        } else {
+            println!(
+                "Game {} Score: {} High score: {}",
+                stats.episode, score, self.high_score
+            );
+        }
+
+        let autosave = agent.episodes / self.options.autosave.max(1);
+        if autosave > self.last_autosave {
+            save_checkpoint(self.options, agent, games);
+            self.last_autosave = autosave;
+        }
+    }
+}
+
+fn save_checkpoint<T: Float>(options: &Options, agent: &Agent<T>, games: &[Game]) {
+    if let Some(dir) = &options.checkpoint {
+        checkpoint::save(dir, agent, games)
+            .unwrap_or_else(|err| panic!("failed to write checkpoint {}: {}", dir, err));
+        println!("Saved checkpoint to {}", dir);
+    }
+}
@@ -0,0 +1,233 @@
+// Everything needed to continue a training run exactly where it stopped,
+// stored as a directory:
+//   generation      number of the latest complete save
+//   save-N/         that save:
+//     network.model   weights (see `NeuralNetwork::save`), which also record
+//                     the precision the run trains in
+//     target.model    target network weights
+//     agent.state     exploration/learning settings and the agent's RNG
+//     memory.bin      replay memory
+//     game-N.state    one file per game, including its food RNG
+//
+// Every save goes into a fresh `save-N` and only then is `generation`
+// switched to it, by renaming a temporary file over it. A save interrupted at
+// any point leaves the previous one in place, never a mix of the two.
+use crate::agent::Agent;
+use crate::float::{Float, Precision};
+use crate::game::Game;
+use crate::nn::{self, NeuralNetwork};
+use crate::persist::invalid_data;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+const NETWORK_FILE: &str = "network.model";
+const TARGET_FILE: &str = "target.model";
+const AGENT_FILE: &str = "agent.state";
+const MEMORY_FILE: &str = "memory.bin";
+const GENERATION_FILE: &str = "generation";
+
+fn game_file(index: usize) -> String {
+    format!("game-{}.state", index)
+}
+
+fn save_dir(generation: u64) -> String {
+    format!("save-{}", generation)
+}
+
+fn write_file<F>(dir: &Path, name: &str, write: F) -> io::Result<()>
+where
+    F: FnOnce(&mut BufWriter<File>) -> io::Result<()>,
+{
+    let mut w = BufWriter::new(File::create(dir.join(name))?);
+    write(&mut w)?;
+    w.into_inner()?.sync_all()
+}
+
+// Number of the latest complete save, if there is one
+fn generation(dir: &Path) -> io::Result<Option<u64>> {
+    match fs::read_to_string(dir.join(GENERATION_FILE)) {
+        Ok(text) => text
+            .trim()
+            .parse()
+            .map(Some)
+            .map_err(|_| invalid_data(format!("invalid checkpoint generation {:?}", text))),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+// Directory of the latest complete save
+fn current_dir(dir: &Path) -> io::Result<PathBuf> {
+    let generation = generation(dir)?.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} holds no checkpoint", dir.display()),
+        )
+    })?;
+    Ok(dir.join(save_dir(generation)))
+}
+
+pub fn save<T: Float, P: AsRef<Path>>(dir: P, agent: &Agent<T>, games: &[Game]) -> io::Result<()> {
+    let dir = dir.as_ref();
+    fs::create_dir_all(dir)?;
+    let generation = generation(dir)?.map_or(0, |generation| generation + 1);
+    let name = save_dir(generation);
+    let save = dir.join(&name);
+    // Left over from a save that was interrupted before it was switched to
+    if save.exists() {
+        fs::remove_dir_all(&save)?;
+    }
+    fs::create_dir(&save)?;
+
+    write_file(&save, NETWORK_FILE, |w| agent.neural_network.write_to(w))?;
+    write_file(&save, TARGET_FILE, |w| agent.target_network.write_to(w))?;
+    write_file(&save, AGENT_FILE, |w| agent.write_state(w))?;
+    write_file(&save, MEMORY_FILE, |w| agent.write_memory(w))?;
+    for (i, game) in games.iter().enumerate() {
+        write_file(&save, &game_file(i), |w| game.write_state(w))?;
+    }
+
+    let tmp_name = format!("{}.tmp", GENERATION_FILE);
+    write_file(dir, &tmp_name, |w| writeln!(w, "{}", generation))?;
+    fs::rename(dir.join(tmp_name), dir.join(GENERATION_FILE))?;
+
+    // Earlier saves, and any an interrupted run left behind
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_name = entry.file_name();
+        let file_name = file_name.to_string_lossy();
+        if file_name.starts_with("save-") && file_name != name {
+            fs::remove_dir_all(entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+// Precision of the run that wrote the checkpoint, so it can be loaded in the
+// same one
+pub fn precision<P: AsRef<Path>>(dir: P) -> io::Result<Precision> {
+    let path = current_dir(dir.as_ref())?.join(NETWORK_FILE);
+    nn::read_precision(&mut BufReader::new(File::open(path)?))
+}
+
+// Returns the agent and however many games the checkpoint holds. The networks
+// are converted to `T` if they were saved in the other precision.
+pub fn load<T: Float, P: AsRef<Path>>(dir: P) -> io::Result<(Agent<T>, Vec<Game>)> {
+    let dir = current_dir(dir.as_ref())?;
+    let open = |name: &str| File::open(dir.join(name)).map(BufReader::new);
+
+    let network = NeuralNetwork::read_from(&mut open(NETWORK_FILE)?)?;
+    let mut agent = Agent::read_state(&mut open(AGENT_FILE)?, network)?;
+    let target = NeuralNetwork::read_from(&mut open(TARGET_FILE)?)?;
+    if !target.same_architecture(&agent.neural_network) {
+        return Err(invalid_data("target network does not match the network"));
+    }
+    agent.target_network = target;
+    agent.read_memory(&mut open(MEMORY_FILE)?)?;
+
+    let mut games = Vec::new();
+    while dir.join(game_file(games.len())).exists() {
+        games.push(Game::read_state(&mut open(&game_file(games.len()))?)?);
+    }
+    Ok((agent, games))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agent::AgentConfig;
+    use crate::game::BoardConfig;
+    use crate::vec_env::{self, VecEnv};
+
+    // Fresh directory per test under the system temp dir
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rusty_snake-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn an_interrupted_save_leaves_the_previous_one_in_place() {
+        let dir = scratch_dir("interrupted");
+        let mut agent: Agent = Agent::with_seed(12, 4, 1);
+        let games = [Game::with_seed(BoardConfig::new(8, 8), 1)];
+        save(&dir, &agent, &games).unwrap();
+
+        // A later save that died halfway through its files
+        agent.epsilon = 0.5;
+        let partial = dir.join(save_dir(1));
+        fs::create_dir(&partial).unwrap();
+        write_file(&partial, AGENT_FILE, |w| agent.write_state(w)).unwrap();
+        let (loaded, _) = load::<f64, _>(&dir).unwrap();
+        assert_eq!(loaded.epsilon, 1.0);
+
+        // The next save replaces the leftovers and everything older
+        save(&dir, &agent, &games).unwrap();
+        let (loaded, _) = load::<f64, _>(&dir).unwrap();
+        assert_eq!(loaded.epsilon, 0.5);
+        let mut saves: Vec<_> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        saves.sort();
+        assert_eq!(saves, [GENERATION_FILE, &save_dir(1)]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn new_run() -> (Agent, VecEnv<Game>) {
+        let config = AgentConfig {
+            memory_capacity: 500,
+            batch_size: 16,
+            n_step: 2,
+            ..AgentConfig::default()
+        };
+        let mut agent = Agent::with_config(12, 4, 9, config);
+        agent.epsilon_decay = 0.99;
+        let board = BoardConfig::new(8, 8);
+        let games = (0..2).map(|i| Game::with_seed(board, 9 + i)).collect();
+        (agent, VecEnv::new(games))
+    }
+
+    // Trains in lockstep like the `train` binary until `episodes` episodes
+    // have been played; returns the number and slot of each one finished
+    fn train_until(
+        agent: &mut Agent,
+        envs: &mut VecEnv<Game>,
+        episodes: usize,
+    ) -> Vec<(usize, usize)> {
+        let mut finished = Vec::new();
+        vec_env::train(agent, envs, episodes, |stats, _, _| {
+            finished.push((stats.episode, stats.worker))
+        });
+        finished
+    }
+
+    #[test]
+    fn ten_games_and_a_resume_to_twenty_match_twenty_straight() {
+        let (mut agent, mut envs) = new_run();
+        let episodes = train_until(&mut agent, &mut envs, 20);
+
+        let dir = scratch_dir("resume");
+        let (mut first, mut first_envs) = new_run();
+        let mut resumed_episodes = train_until(&mut first, &mut first_envs, 10);
+        save(&dir, &first, first_envs.envs()).unwrap();
+        let (mut resumed, games) = load(&dir).unwrap();
+        assert_eq!(games.len(), 2);
+        let mut resumed_envs = VecEnv::resume(games);
+        resumed_episodes.extend(train_until(&mut resumed, &mut resumed_envs, 20));
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(resumed_episodes, episodes);
+        assert_eq!(resumed.episodes, agent.episodes);
+        let state = |agent: &Agent| {
+            let mut bytes = Vec::new();
+            agent.neural_network.write_to(&mut bytes).unwrap();
+            agent.target_network.write_to(&mut bytes).unwrap();
+            agent.write_state(&mut bytes).unwrap();
+            agent.write_memory(&mut bytes).unwrap();
+            bytes
+        };
+        assert_eq!(state(&resumed), state(&agent));
+    }
+}
@@ -0,0 +1,31 @@
+// Interface shared by everything the agent can be trained on. Observations are
+// flat feature vectors and actions are indices in `0..action_count()`, so
+// board variants or other encodings only need a new implementation of this
+// trait.
+pub trait Environment {
+    type Event;
+
+    // Start a new episode and return its first observation
+    fn reset(&mut self) -> Vec<f64>;
+
+    fn step(&mut self, action: usize) -> StepOutcome<Self::Event>;
+
+    // Observation of the current state, without advancing it
+    fn observation(&mut self) -> Vec<f64>;
+
+    fn observation_size(&self) -> usize;
+
+    fn action_count(&self) -> usize;
+
+    // Reseed the environment's randomness, replacing its generator straight
+    // away: the rest of the current episode already draws from the new seed
+    fn seed(&mut self, seed: u64);
+}
+
+// Result of a single environment step
+pub struct StepOutcome<E> {
+    pub observation: Vec<f64>,
+    pub reward: f64,
+    pub done: bool,
+    pub events: Vec<E>,
+}
@@ -0,0 +1,163 @@
+// Element types `Matrix` and `NeuralNetwork` can compute in: f64 by default,
+// f32 for networks that train faster in half the memory. Hyperparameters
+// (learning rates, discounts, clip norms) stay f64 and are converted with
+// `from_f64` where they meet the weights, which is exact for f64.
+use crate::persist::{self, invalid_data};
+use std::cmp::Ordering;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::iter::Sum;
+use std::ops::{Add, AddAssign, Div, DivAssign, Mul, MulAssign, Neg, Sub, SubAssign};
+use std::str::FromStr;
+
+pub trait Float:
+    Copy
+    + Default
+    + PartialOrd
+    + fmt::Debug
+    + fmt::Display
+    + Send
+    + Sync
+    + 'static
+    + Add<Output = Self>
+    + Sub<Output = Self>
+    + Mul<Output = Self>
+    + Div<Output = Self>
+    + Neg<Output = Self>
+    + AddAssign
+    + SubAssign
+    + MulAssign
+    + DivAssign
+    + Sum
+    + for<'a> Sum<&'a Self>
+{
+    const ZERO: Self;
+    const ONE: Self;
+    const PRECISION: Precision;
+
+    // Rounds to the nearest value for f32
+    fn from_f64(value: f64) -> Self;
+    fn to_f64(self) -> f64;
+
+    fn sqrt(self) -> Self;
+    fn exp(self) -> Self;
+    // e^x - 1, accurate near 0
+    fn exp_m1(self) -> Self;
+    fn tanh(self) -> Self;
+    fn powi(self, n: i32) -> Self;
+    fn abs(self) -> Self;
+    fn max(self, other: Self) -> Self;
+    fn total_cmp(&self, other: &Self) -> Ordering;
+
+    // Little-endian, in this type's own width
+    fn write_to<W: Write>(self, w: &mut W) -> io::Result<()>;
+    fn read_from<R: Read>(r: &mut R) -> io::Result<Self>;
+}
+
+macro_rules! impl_float {
+    ($float:ident, $precision:expr, $write:path, $read:path) => {
+        impl Float for $float {
+            const ZERO: Self = 0.0;
+            const ONE: Self = 1.0;
+            const PRECISION: Precision = $precision;
+
+            fn from_f64(value: f64) -> Self {
+                value as $float
+            }
+
+            fn to_f64(self) -> f64 {
+                self as f64
+            }
+
+            fn sqrt(self) -> Self {
+                $float::sqrt(self)
+            }
+
+            fn exp(self) -> Self {
+                $float::exp(self)
+            }
+
+            fn exp_m1(self) -> Self {
+                $float::exp_m1(self)
+            }
+
+            fn tanh(self) -> Self {
+                $float::tanh(self)
+            }
+
+            fn powi(self, n: i32) -> Self {
+                $float::powi(self, n)
+            }
+
+            fn abs(self) -> Self {
+                $float::abs(self)
+            }
+
+            fn max(self, other: Self) -> Self {
+                $float::max(self, other)
+            }
+
+            fn total_cmp(&self, other: &Self) -> Ordering {
+                $float::total_cmp(self, other)
+            }
+
+            fn write_to<W: Write>(self, w: &mut W) -> io::Result<()> {
+                $write(w, self)
+            }
+
+            fn read_from<R: Read>(r: &mut R) -> io::Result<Self> {
+                $read(r)
+            }
+        }
+    };
+}
+
+impl_float!(f32, Precision::F32, persist::write_f32, persist::read_f32);
+impl_float!(f64, Precision::F64, persist::write_f64, persist::read_f64);
+
+// Which `Float` a network computes in, as recorded in model files
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Precision {
+    F32,
+    F64,
+}
+
+impl Precision {
+    pub fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        let tag = match self {
+            Precision::F64 => 0,
+            Precision::F32 => 1,
+        };
+        persist::write_u8(w, tag)
+    }
+
+    pub fn read_from<R: Read>(r: &mut R) -> io::Result<Precision> {
+        match persist::read_u8(r)? {
+            0 => Ok(Precision::F64),
+            1 => Ok(Precision::F32),
+            other => Err(invalid_data(format!("invalid precision {}", other))),
+        }
+    }
+}
+
+impl fmt::Display for Precision {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Precision::F32 => write!(f, "f32"),
+            Precision::F64 => write!(f, "f64"),
+        }
+    }
+}
+
+// "f32" or "f64"
+impl FromStr for Precision {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "f32" => Ok(Precision::F32),
+            "f64" => Ok(Precision::F64),
+            _ => Err(format!("unknown precision {:?}, expected f32 or f64", s)),
+        }
+    }
+}
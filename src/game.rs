@@ -1,10 +1,82 @@
-use crate::agent::Action;
-use ::rand::thread_rng;
-use ::rand::Rng;
-use macroquad::prelude::*;
+use crate::env::{Environment, StepOutcome};
+use crate::persist::{self, invalid_data};
+use crate::rng::{self, SimRng};
+use rand::Rng;
 use std::cmp::Ordering;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::str::FromStr;
 
-pub const TILE_SIZE: f32 = 10.0;
+const GAME_MAGIC: &[u8; 4] = b"RSGM";
+const GAME_VERSION: u32 = 1;
+
+// Playing field size in cells
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BoardConfig {
+    pub width: i16,
+    pub height: i16,
+}
+
+impl BoardConfig {
+    // Smallest board the starting snake fits on
+    pub const MIN_SIZE: i16 = 6;
+
+    pub fn new(width: i16, height: i16) -> Self {
+        Self::try_new(width, height).unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    pub fn try_new(width: i16, height: i16) -> Result<Self, String> {
+        if width < Self::MIN_SIZE || height < Self::MIN_SIZE {
+            return Err(format!(
+                "board must be at least {0}x{0} cells, got {1}x{2}",
+                Self::MIN_SIZE,
+                width,
+                height
+            ));
+        }
+        Ok(Self { width, height })
+    }
+
+    pub fn contains(&self, cell: Cell) -> bool {
+        cell.x >= 0 && cell.x < self.width && cell.y >= 0 && cell.y < self.height
+    }
+}
+
+impl Default for BoardConfig {
+    fn default() -> Self {
+        Self::new(40, 40)
+    }
+}
+
+// Parses `WIDTHxHEIGHT`, e.g. `20x20`
+impl FromStr for BoardConfig {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (width, height) = s
+            .split_once('x')
+            .ok_or_else(|| format!("expected WIDTHxHEIGHT, got {:?}", s))?;
+        let width: i16 = width
+            .parse()
+            .map_err(|_| format!("invalid board width {:?}", width))?;
+        let height: i16 = height
+            .parse()
+            .map_err(|_| format!("invalid board height {:?}", height))?;
+        Self::try_new(width, height)
+    }
+}
+
+// Board position in whole cells, (0, 0) being the top-left corner. Mapping to
+// pixels is left to the renderer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Cell {
+    pub x: i16,
+    pub y: i16,
+}
+
+pub const fn cell(x: i16, y: i16) -> Cell {
+    Cell { x, y }
+}
 
 pub enum GameStatus {
     Start,
@@ -12,6 +84,60 @@ pub enum GameStatus {
     GameOver,
 }
 
+const OBSERVATION_SIZE: usize = 12;
+
+#[derive(Hash, PartialEq, Eq, Clone, Copy, Debug)]
+pub enum Action {
+    Up,
+    Down,
+    Left,
+    Right,
+}
+
+impl Action {
+    pub const ALL: [Action; 4] = [Action::Up, Action::Down, Action::Left, Action::Right];
+
+    pub fn from_index(index: usize) -> Action {
+        match index {
+            0 => Action::Up,
+            1 => Action::Down,
+            2 => Action::Left,
+            3 => Action::Right,
+            _ => panic!("Unexpected action index"),
+        }
+    }
+
+    pub fn index(&self) -> usize {
+        match self {
+            Action::Up => 0,
+            Action::Down => 1,
+            Action::Left => 2,
+            Action::Right => 3,
+        }
+    }
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Action::Up => write!(f, "Up"),
+            Action::Down => write!(f, "Down"),
+            Action::Left => write!(f, "Left"),
+            Action::Right => write!(f, "Right"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GameEvent {
+    AteFood,
+    HitWall,
+    HitSelf,
+    Starved,
+    // The snake filled the whole board
+    Won,
+}
+
 #[derive(PartialEq)]
 pub enum Direction {
     Up,
@@ -21,8 +147,8 @@ pub enum Direction {
 }
 
 pub struct Game {
-    pub snake: Vec<Vec2>,
-    pub food: Vec2,
+    pub snake: Vec<Cell>,
+    pub food: Cell,
     pub direction: Direction,
     pub direction_lock: bool,
     pub running: bool,
@@ -33,18 +159,25 @@ pub struct Game {
     pub time_starving: i32,
     pub just_ate: bool,
     pub n_games: i32,
+    pub board: BoardConfig,
+    rng: SimRng,
 }
 
 impl Game {
-    pub fn new() -> Self {
+    pub fn new(board: BoardConfig) -> Self {
+        Self::with_seed(board, rng::random_seed())
+    }
+
+    pub fn with_seed(board: BoardConfig, seed: u64) -> Self {
+        Self::from_rng(board, rng::stream(seed, rng::GAME_STREAM))
+    }
+
+    fn from_rng(board: BoardConfig, rng: SimRng) -> Self {
+        // Start in the middle of the board heading up
+        let (x, y) = (board.width / 2 - 1, board.height / 2 - 1);
         let mut new_game = Self {
-            snake: vec![
-                vec2(200.0, 200.0),
-                vec2(200.0, 210.0),
-                vec2(200.0, 220.0),
-                vec2(200.0, 230.0),
-            ],
-            food: Vec2::ZERO, // Placeholder, you'll generate a position for it
+            snake: vec![cell(x, y), cell(x, y + 1), cell(x, y + 2), cell(x, y + 3)],
+            food: cell(0, 0), // Placeholder, you'll generate a position for it
             direction: Direction::Up,
             direction_lock: false,
             running: true,
@@ -55,8 +188,12 @@ impl Game {
             game_status: GameStatus::Start,
             time_starving: 0,
             just_ate: false,
+            board,
+            rng,
         };
-        new_game.food = new_game.new_food();
+        new_game.food = new_game
+            .new_food()
+            .expect("the starting snake leaves free cells");
         new_game
     }
 
@@ -64,10 +201,10 @@ impl Game {
         let (x, y) = (self.snake[0].x, self.snake[0].y);
 
         let new_head = match self.direction {
-            Direction::Up => vec2(x, y - TILE_SIZE),
-            Direction::Down => vec2(x, y + TILE_SIZE),
-            Direction::Left => vec2(x - TILE_SIZE, y),
-            Direction::Right => vec2(x + TILE_SIZE, y),
+            Direction::Up => cell(x, y - 1),
+            Direction::Down => cell(x, y + 1),
+            Direction::Left => cell(x - 1, y),
+            Direction::Right => cell(x + 1, y),
         };
 
         self.snake.insert(0, new_head);
@@ -75,40 +212,31 @@ impl Game {
         self.direction_lock = false;
     }
 
-    pub fn handle_input(&mut self) -> Action {
-        if self.direction_lock == false {
-            if is_key_pressed(KeyCode::Up) && self.direction != Direction::Down {
-                self.direction = Direction::Up;
-                self.direction_lock = true;
-                Action::Up
-            } else if is_key_pressed(KeyCode::Down) && self.direction != Direction::Up {
-                self.direction = Direction::Down;
-                self.direction_lock = true;
-                Action::Down
-            } else if is_key_pressed(KeyCode::Left) && self.direction != Direction::Right {
-                self.direction = Direction::Left;
-                self.direction_lock = true;
-                Action::Left
-            } else if is_key_pressed(KeyCode::Right) && self.direction != Direction::Left {
-                self.direction = Direction::Right;
-                self.direction_lock = true;
-                Action::Right
-            } else {
-                match self.direction {
-                    Direction::Up => Action::Up,
-                    Direction::Down => Action::Down,
-                    Direction::Left => Action::Left,
-                    Direction::Right => Action::Right,
-                }
-            }
-        } else {
-            // return the current action when direction_lock is true.
-            match self.direction {
-                Direction::Up => Action::Up,
-                Direction::Down => Action::Down,
-                Direction::Left => Action::Left,
-                Direction::Right => Action::Right,
-            }
+    // Turn from player input; locked until the snake has moved so two quick
+    // key presses can't reverse it into itself.
+    pub fn steer(&mut self, action: &Action) -> bool {
+        if self.direction_lock {
+            return false;
+        }
+        let allowed = match action {
+            Action::Up => self.direction != Direction::Down,
+            Action::Down => self.direction != Direction::Up,
+            Action::Left => self.direction != Direction::Right,
+            Action::Right => self.direction != Direction::Left,
+        };
+        if allowed {
+            self.handle_action(action);
+            self.direction_lock = true;
+        }
+        allowed
+    }
+
+    pub fn current_action(&self) -> Action {
+        match self.direction {
+            Direction::Up => Action::Up,
+            Direction::Down => Action::Down,
+            Direction::Left => Action::Left,
+            Direction::Right => Action::Right,
         }
     }
 
@@ -128,33 +256,38 @@ impl Game {
         };
     }
 
-    pub fn new_food(&self) -> Vec2 {
+    // A random cell the snake is not on, or `None` once the snake (counting
+    // the segment it is about to grow) covers the whole board
+    pub fn new_food(&mut self) -> Option<Cell> {
+        let cells = self.board.width as usize * self.board.height as usize;
+        if self.snake.len() >= cells {
+            return None;
+        }
         loop {
-            let mut rng = thread_rng();
-            let x = (rng.gen_range(2..=40) as f32) * TILE_SIZE;
-            let y = (rng.gen_range(2..=40) as f32) * TILE_SIZE;
-            let food_location = vec2(x, y);
+            let x = self.rng.gen_range(0..self.board.width);
+            let y = self.rng.gen_range(0..self.board.height);
+            let food_location = cell(x, y);
 
             if !self.snake.contains(&food_location) {
-                return food_location;
+                return Some(food_location);
             }
         }
     }
 
-    pub fn collision_with_self(&mut self) {
-        for segment in &self.snake[1..] {
-            if *segment == self.snake[0] {
-                self.running = false;
-                break;
-            }
+    pub fn collision_with_self(&mut self) -> bool {
+        let hit = self.snake[1..].contains(&self.snake[0]);
+        if hit {
+            self.running = false;
         }
+        hit
     }
 
-    pub fn collision_with_border(&mut self) {
-        let head = self.snake[0];
-        if head.x < 10.0 || head.x >= 410.0 || head.y < 10.0 || head.y >= 410.0 {
+    pub fn collision_with_border(&mut self) -> bool {
+        let hit = !self.board.contains(self.snake[0]);
+        if hit {
             self.running = false;
         }
+        hit
     }
 
     pub fn collision_with_food(&mut self) -> bool {
@@ -170,28 +303,176 @@ impl Game {
         false
     }
 
+    // Advance the game by one tick: apply the action, move, resolve collisions
+    // and food, and hand back the new observation with its reward.
+    pub fn step(&mut self, action: &Action) -> StepOutcome<GameEvent> {
+        let mut events = Vec::new();
+
+        self.handle_action(action);
+        self.move_snake();
+        // Steps since the last food. The original loop never advanced this,
+        // so the starvation penalty below could not trigger; counting it
+        // ends up penalising a snake that circles forever without eating.
+        self.time_starving += 1;
+
+        if self.collision_with_border() {
+            events.push(GameEvent::HitWall);
+        }
+        if self.collision_with_self() {
+            events.push(GameEvent::HitSelf);
+        }
+
+        // Past this many steps without food every step costs 0.01 more, down
+        // to -0.5 a step
+        let starving_limit = 10000 + (self.snake.len() as i32) * 10;
+        let mut reward: f64 = 0.0;
+        if !self.running {
+            reward = -10.0;
+            self.game_status = GameStatus::GameOver;
+        } else if self.collision_with_food() {
+            reward = 5.0;
+            events.push(GameEvent::AteFood);
+            match self.new_food() {
+                Some(food) => self.food = food,
+                None => {
+                    // Nowhere left to go: the episode ends as a win
+                    self.running = false;
+                    self.game_status = GameStatus::GameOver;
+                    events.push(GameEvent::Won);
+                }
+            }
+        } else if self.time_starving > starving_limit {
+            let calculated_value = (self.time_starving - starving_limit) as f64 * 0.01;
+            reward = (-calculated_value).max(-0.5);
+            events.push(GameEvent::Starved);
+        }
+
+        StepOutcome {
+            observation: self.observation(),
+            reward,
+            done: !self.running,
+            events,
+        }
+    }
+
     pub fn restart(&mut self) {
         if self.score > self.high_score {
             self.high_score = self.score;
         }
         let current_high_score = self.high_score; // Store the current high score before reinitializing
-        let ngames = self.n_games;
-        *self = Game::new();
+        let ngames = self.n_games + i32::from(!self.running);
+        *self = Game::from_rng(self.board, self.rng.clone());
         self.high_score = current_high_score; // Set the high score in the new instance
-        self.n_games = ngames + 1;
+        self.n_games = ngames;
+    }
+
+    pub fn observation(&mut self) -> Vec<f64> {
+        self.get_game_state().iter().map(|&x| x as f64).collect()
+    }
+
+    // Full game state, including a game in progress and the food RNG
+    pub fn write_state<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        persist::write_magic(w, GAME_MAGIC, GAME_VERSION)?;
+        persist::write_i32(w, self.board.width as i32)?;
+        persist::write_i32(w, self.board.height as i32)?;
+        persist::write_usize(w, self.snake.len())?;
+        for segment in self.snake.iter().chain([&self.food]) {
+            persist::write_i32(w, segment.x as i32)?;
+            persist::write_i32(w, segment.y as i32)?;
+        }
+        let direction = match self.direction {
+            Direction::Up => 0,
+            Direction::Down => 1,
+            Direction::Left => 2,
+            Direction::Right => 3,
+        };
+        persist::write_u8(w, direction)?;
+        persist::write_bool(w, self.direction_lock)?;
+        persist::write_bool(w, self.running)?;
+        persist::write_f64(w, self.speed)?;
+        persist::write_i32(w, self.score)?;
+        persist::write_i32(w, self.high_score)?;
+        let status = match self.game_status {
+            GameStatus::Start => 0,
+            GameStatus::Running => 1,
+            GameStatus::GameOver => 2,
+        };
+        persist::write_u8(w, status)?;
+        persist::write_i32(w, self.time_starving)?;
+        persist::write_bool(w, self.just_ate)?;
+        persist::write_i32(w, self.n_games)?;
+        rng::write_state(w, &self.rng)
+    }
+
+    pub fn read_state<R: Read>(r: &mut R) -> io::Result<Game> {
+        let version = persist::read_magic(r, GAME_MAGIC)?;
+        persist::check_version("game state", version, GAME_VERSION)?;
+
+        let read_i16 = |r: &mut R| -> io::Result<i16> {
+            i16::try_from(persist::read_i32(r)?)
+                .map_err(|_| invalid_data("coordinate out of range"))
+        };
+        let width = read_i16(r)?;
+        let height = read_i16(r)?;
+        let board = BoardConfig::try_new(width, height).map_err(invalid_data)?;
+
+        let snake_len = persist::read_usize(r)?;
+        let mut cells = (0..=snake_len)
+            .map(|_| Ok(cell(read_i16(r)?, read_i16(r)?)))
+            .collect::<io::Result<Vec<_>>>()?;
+        let food = cells.pop().unwrap();
+        if cells.is_empty() {
+            return Err(invalid_data("snake has no segments"));
+        }
+
+        let direction = match persist::read_u8(r)? {
+            0 => Direction::Up,
+            1 => Direction::Down,
+            2 => Direction::Left,
+            3 => Direction::Right,
+            other => return Err(invalid_data(format!("invalid direction {}", other))),
+        };
+        let direction_lock = persist::read_bool(r)?;
+        let running = persist::read_bool(r)?;
+        let speed = persist::read_f64(r)?;
+        let score = persist::read_i32(r)?;
+        let high_score = persist::read_i32(r)?;
+        let game_status = match persist::read_u8(r)? {
+            0 => GameStatus::Start,
+            1 => GameStatus::Running,
+            2 => GameStatus::GameOver,
+            other => return Err(invalid_data(format!("invalid game status {}", other))),
+        };
+
+        Ok(Self {
+            snake: cells,
+            food,
+            direction,
+            direction_lock,
+            running,
+            speed,
+            score,
+            high_score,
+            game_status,
+            time_starving: persist::read_i32(r)?,
+            just_ate: persist::read_bool(r)?,
+            n_games: persist::read_i32(r)?,
+            board,
+            rng: rng::read_state(r)?,
+        })
     }
 
-    pub fn get_game_state(&mut self) -> [i32; 12] {
+    pub fn get_game_state(&mut self) -> [i32; OBSERVATION_SIZE] {
         let direction_state: [i32; 4] = match self.direction {
             Direction::Up => [1, 0, 0, 0],
             Direction::Down => [0, 1, 0, 0],
             Direction::Left => [0, 0, 1, 0],
             Direction::Right => [0, 0, 0, 1],
         };
-        let head_x = self.snake[0].x as i32;
-        let head_y = self.snake[0].y as i32;
-        let food_x = self.food.x as i32;
-        let food_y = self.food.y as i32;
+        let head_x = self.snake[0].x;
+        let head_y = self.snake[0].y;
+        let food_x = self.food.x;
+        let food_y = self.food.y;
         let mut food_direction: [i32; 4] = [0, 0, 0, 0];
         if self.just_ate {
             self.just_ate = false;
@@ -217,36 +498,35 @@ impl Game {
         }
         let mut danger = [0, 0, 0, 0];
         if self.snake.len() > 4 {
-            println!("longer than 4");
             for segment in &self.snake[1..] {
-                let x_seg = segment.x as i32;
-                let y_seg = segment.y as i32;
+                let x_seg = segment.x;
+                let y_seg = segment.y;
 
-                if (head_x - x_seg).abs() + (head_y - y_seg).abs() == 10 {
-                    if ((head_y - y_seg == 10) && (self.direction != Direction::Down))
-                        || (head_y - 10 == 0)
+                if (head_x - x_seg).abs() + (head_y - y_seg).abs() == 1 {
+                    if ((head_y - y_seg == 1) && (self.direction != Direction::Down))
+                        || (head_y == 0)
                     {
                         danger[0] = 1;
                     }
-                    if ((head_y - y_seg == -10) && (self.direction != Direction::Up))
-                        || (head_y + 10 == 410)
+                    if ((head_y - y_seg == -1) && (self.direction != Direction::Up))
+                        || (head_y + 1 == self.board.height)
                     {
                         danger[1] = 1;
                     }
-                    if ((head_x - x_seg == 10) && (self.direction != Direction::Right))
-                        || (head_x - 10 == 0)
+                    if ((head_x - x_seg == 1) && (self.direction != Direction::Right))
+                        || (head_x == 0)
                     {
                         danger[2] = 1;
                     }
-                    if ((head_x - x_seg == -10) && (self.direction != Direction::Left))
-                        || (head_x + 10 == 410)
+                    if ((head_x - x_seg == -1) && (self.direction != Direction::Left))
+                        || (head_x + 1 == self.board.width)
                     {
                         danger[3] = 1;
                     }
                 }
             }
         }
-        let game_state: [i32; 12] = direction_state
+        let game_state: [i32; OBSERVATION_SIZE] = direction_state
             .iter()
             .chain(&food_direction)
             .chain(&danger)
@@ -258,3 +538,157 @@ impl Game {
         game_state
     }
 }
+
+impl Environment for Game {
+    type Event = GameEvent;
+
+    fn reset(&mut self) -> Vec<f64> {
+        self.restart();
+        self.observation()
+    }
+
+    fn step(&mut self, action: usize) -> StepOutcome<GameEvent> {
+        Game::step(self, &Action::from_index(action))
+    }
+
+    fn observation(&mut self) -> Vec<f64> {
+        Game::observation(self)
+    }
+
+    fn observation_size(&self) -> usize {
+        OBSERVATION_SIZE
+    }
+
+    fn action_count(&self) -> usize {
+        Action::ALL.len()
+    }
+
+    fn seed(&mut self, seed: u64) {
+        self.rng = rng::stream(seed, rng::GAME_STREAM);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 6x6 board: the snake starts with its head at (2, 2) heading up
+    fn small_game() -> Game {
+        let mut game = Game::with_seed(BoardConfig::new(6, 6), 1);
+        game.food = cell(5, 5);
+        game
+    }
+
+    #[test]
+    fn running_into_the_wall_ends_the_episode() {
+        let mut game = small_game();
+        for _ in 0..2 {
+            let outcome = game.step(&Action::Up);
+            assert!(!outcome.done && outcome.events.is_empty());
+            assert_eq!(outcome.reward, 0.0);
+        }
+        let outcome = game.step(&Action::Up);
+        assert!(outcome.done);
+        assert_eq!(outcome.reward, -10.0);
+        assert_eq!(outcome.events, [GameEvent::HitWall]);
+    }
+
+    #[test]
+    fn running_into_itself_ends_the_episode() {
+        let mut game = small_game();
+        game.snake = vec![cell(2, 2), cell(3, 2), cell(3, 3), cell(2, 3), cell(1, 3)];
+        game.direction = Direction::Left;
+        let outcome = game.step(&Action::Down);
+        assert!(outcome.done);
+        assert_eq!(outcome.reward, -10.0);
+        assert_eq!(outcome.events, [GameEvent::HitSelf]);
+    }
+
+    #[test]
+    fn eating_grows_the_snake_and_moves_the_food() {
+        let mut game = small_game();
+        game.food = cell(2, 1);
+        game.time_starving = 50;
+        let outcome = game.step(&Action::Up);
+        assert!(!outcome.done);
+        assert_eq!(outcome.reward, 5.0);
+        assert_eq!(outcome.events, [GameEvent::AteFood]);
+        assert_eq!(
+            (game.score, game.snake.len(), game.time_starving),
+            (1, 5, 0)
+        );
+        assert!(!game.snake.contains(&game.food));
+    }
+
+    #[test]
+    fn eating_the_last_free_cell_wins() {
+        let mut game = small_game();
+        // Snake the body back and forth over every row, leaving only (0, 0)
+        // free with the head right next to it
+        let path: Vec<Cell> = (0..6)
+            .flat_map(|y| (0..6).map(move |x| cell(if y % 2 == 0 { x } else { 5 - x }, y)))
+            .collect();
+        game.snake = path[1..].to_vec();
+        game.direction = Direction::Left;
+        game.food = path[0];
+        let outcome = game.step(&Action::Left);
+        assert!(outcome.done);
+        assert_eq!(outcome.reward, 5.0);
+        assert_eq!(outcome.events, [GameEvent::AteFood, GameEvent::Won]);
+        assert_eq!(game.snake.len(), 36);
+        assert_eq!(game.new_food(), None);
+    }
+
+    #[test]
+    fn starving_costs_more_every_step_up_to_a_cap() {
+        let mut game = small_game();
+        let limit = 10_000 + 4 * 10;
+        game.time_starving = limit;
+        let outcome = game.step(&Action::Left);
+        assert!(!outcome.done);
+        assert!((outcome.reward + 0.01).abs() < 1e-12);
+        assert_eq!(outcome.events, [GameEvent::Starved]);
+
+        game.time_starving = limit + 100;
+        let outcome = game.step(&Action::Down);
+        assert_eq!(outcome.reward, -0.5);
+        assert_eq!(outcome.events, [GameEvent::Starved]);
+    }
+
+    #[test]
+    fn saved_games_continue_exactly() {
+        let mut game = Game::with_seed(BoardConfig::new(8, 10), 5);
+        for action in [Action::Left, Action::Down, Action::Down, Action::Right] {
+            game.step(&action);
+        }
+        let mut bytes = Vec::new();
+        game.write_state(&mut bytes).unwrap();
+        let mut loaded = Game::read_state(&mut bytes.as_slice()).unwrap();
+        let mut again = Vec::new();
+        loaded.write_state(&mut again).unwrap();
+        assert_eq!(again, bytes);
+
+        // Including where the food turns up next
+        for step in 0..200 {
+            let action = Action::from_index(step % 3);
+            let (expected, outcome) = (game.step(&action), loaded.step(&action));
+            assert_eq!(outcome.observation, expected.observation);
+            assert_eq!(outcome.events, expected.events);
+            assert_eq!(loaded.food, game.food);
+            if expected.done {
+                game.restart();
+                loaded.restart();
+            }
+        }
+    }
+
+    #[test]
+    fn corrupt_game_states_are_rejected() {
+        let mut bytes = Vec::new();
+        small_game().write_state(&mut bytes).unwrap();
+        // Board width, right after the magic and version
+        bytes[8..12].copy_from_slice(&3i32.to_le_bytes());
+        let error = Game::read_state(&mut bytes.as_slice()).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,20 @@
+use macroquad::prelude::*;
+use rusty_snake::game::Action;
+use rusty_snake::game::Game;
+
+const KEY_BINDINGS: [(KeyCode, Action); 4] = [
+    (KeyCode::Up, Action::Up),
+    (KeyCode::Down, Action::Down),
+    (KeyCode::Left, Action::Left),
+    (KeyCode::Right, Action::Right),
+];
+
+// Steer the snake from the arrow keys and return the action it ends up taking
+pub fn handle_input(game: &mut Game) -> Action {
+    for (key, action) in KEY_BINDINGS {
+        if is_key_pressed(key) && game.steer(&action) {
+            break;
+        }
+    }
+    game.current_action()
+}
@@ -0,0 +1,357 @@
+// Inner loops of the `Matrix` operations, on row-major slices of f32 or f64.
+//
+// The `scalar` loops are the reference. With the `simd` feature the products
+// run on chunks of `LANES` values held in fixed-size arrays, which the
+// compiler keeps in vector registers (a single f32x8 one for f32, two f64x4
+// for f64); dot products then add eight partial sums, so they can differ
+// from the scalar order in the last bits.
+// With the `parallel` feature large products and element-wise operations are
+// split into bands of rows on rayon's thread pool. Each element is still
+// computed by a single thread in the same order, so splitting the work
+// changes no results.
+use crate::float::Float;
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+
+#[cfg(not(feature = "simd"))]
+use scalar::{axpy, dot, zip};
+#[cfg(feature = "simd")]
+use simd::{axpy, dot, zip};
+
+// Values per `simd` chunk
+pub const LANES: usize = 8;
+
+// Rows and columns of `multiply`'s tiles: a block of the right-hand side
+// (64 x 64 values, 32 KiB) stays in cache while it is reused for every row
+// of the left-hand side
+const BLOCK: usize = 64;
+
+// Multiply-adds (or elements) below which threads cost more than they save
+#[cfg(feature = "parallel")]
+const PARALLEL_WORK: usize = 1 << 16;
+
+// Rows per rayon task in the products, elements per task otherwise
+#[cfg(feature = "parallel")]
+const BAND_ROWS: usize = 8;
+#[cfg(feature = "parallel")]
+const BAND_ELEMENTS: usize = 1 << 12;
+
+mod scalar {
+    use crate::float::Float;
+
+    // out += a * b
+    pub fn axpy<T: Float>(out: &mut [T], a: T, b: &[T]) {
+        for (o, &b) in out.iter_mut().zip(b) {
+            *o += a * b;
+        }
+    }
+
+    pub fn dot<T: Float>(a: &[T], b: &[T]) -> T {
+        let mut sum = T::ZERO;
+        for (&a, &b) in a.iter().zip(b) {
+            sum += a * b;
+        }
+        sum
+    }
+
+    // a = f(a, b) element by element
+    pub fn zip<T: Float, F: Fn(T, T) -> T>(a: &mut [T], b: &[T], f: &F) {
+        for (x, &y) in a.iter_mut().zip(b) {
+            *x = f(*x, y);
+        }
+    }
+}
+
+#[cfg_attr(not(feature = "simd"), allow(dead_code))]
+mod simd {
+    use super::{scalar, LANES};
+    use crate::float::Float;
+
+    pub fn axpy<T: Float>(out: &mut [T], a: T, b: &[T]) {
+        let len = out.len().min(b.len());
+        let (out, b) = (&mut out[..len], &b[..len]);
+        let mut out_chunks = out.chunks_exact_mut(LANES);
+        let mut b_chunks = b.chunks_exact(LANES);
+        for (o, b) in (&mut out_chunks).zip(&mut b_chunks) {
+            let o: &mut [T; LANES] = o.try_into().unwrap();
+            let b: &[T; LANES] = b.try_into().unwrap();
+            for lane in 0..LANES {
+                o[lane] += a * b[lane];
+            }
+        }
+        scalar::axpy(out_chunks.into_remainder(), a, b_chunks.remainder());
+    }
+
+    pub fn dot<T: Float>(a: &[T], b: &[T]) -> T {
+        let len = a.len().min(b.len());
+        let (a, b) = (&a[..len], &b[..len]);
+        let mut sums = [T::ZERO; LANES];
+        let mut a_chunks = a.chunks_exact(LANES);
+        let mut b_chunks = b.chunks_exact(LANES);
+        for (a, b) in (&mut a_chunks).zip(&mut b_chunks) {
+            let a: &[T; LANES] = a.try_into().unwrap();
+            let b: &[T; LANES] = b.try_into().unwrap();
+            for lane in 0..LANES {
+                sums[lane] += a[lane] * b[lane];
+            }
+        }
+        sums.iter().sum::<T>() + scalar::dot(a_chunks.remainder(), b_chunks.remainder())
+    }
+
+    pub fn zip<T: Float, F: Fn(T, T) -> T>(a: &mut [T], b: &[T], f: &F) {
+        let len = a.len().min(b.len());
+        let (a, b) = (&mut a[..len], &b[..len]);
+        let mut a_chunks = a.chunks_exact_mut(LANES);
+        let mut b_chunks = b.chunks_exact(LANES);
+        for (x, y) in (&mut a_chunks).zip(&mut b_chunks) {
+            let x: &mut [T; LANES] = x.try_into().unwrap();
+            let y: &[T; LANES] = y.try_into().unwrap();
+            for lane in 0..LANES {
+                x[lane] = f(x[lane], y[lane]);
+            }
+        }
+        scalar::zip(a_chunks.into_remainder(), b_chunks.remainder(), f);
+    }
+}
+
+// out (rows x m) += a (rows x n) * b (n x m), where `out` starts zeroed
+pub fn multiply<T: Float>(a: &[T], b: &[T], out: &mut [T], n: usize, m: usize) {
+    if n == 0 || m == 0 {
+        return;
+    }
+    #[cfg(feature = "parallel")]
+    if a.len() * m >= PARALLEL_WORK {
+        out.par_chunks_mut(BAND_ROWS * m)
+            .zip(a.par_chunks(BAND_ROWS * n))
+            .for_each(|(out, a)| multiply_rows(a, b, out, n, m));
+        return;
+    }
+    multiply_rows(a, b, out, n, m);
+}
+
+// i-k-j over tiles of `b`, so the inner loop streams along contiguous rows;
+// each element still sums its products in k order
+fn multiply_rows<T: Float>(a: &[T], b: &[T], out: &mut [T], n: usize, m: usize) {
+    for k0 in (0..n).step_by(BLOCK) {
+        let k1 = (k0 + BLOCK).min(n);
+        for j0 in (0..m).step_by(BLOCK) {
+            let j1 = (j0 + BLOCK).min(m);
+            for (a_row, out_row) in a.chunks_exact(n).zip(out.chunks_exact_mut(m)) {
+                let out_row = &mut out_row[j0..j1];
+                for (k, &a) in a_row.iter().enumerate().take(k1).skip(k0) {
+                    axpy(out_row, a, &b[k * m + j0..k * m + j1]);
+                }
+            }
+        }
+    }
+}
+
+// out (rows x p) = a (rows x n) * bᵀ, where b is p x n
+pub fn multiply_transposed<T: Float>(a: &[T], b: &[T], out: &mut [T], n: usize, p: usize) {
+    if n == 0 || p == 0 {
+        return;
+    }
+    #[cfg(feature = "parallel")]
+    if a.len() * p >= PARALLEL_WORK {
+        out.par_chunks_mut(BAND_ROWS * p)
+            .zip(a.par_chunks(BAND_ROWS * n))
+            .for_each(|(out, a)| multiply_transposed_rows(a, b, out, n, p));
+        return;
+    }
+    multiply_transposed_rows(a, b, out, n, p);
+}
+
+fn multiply_transposed_rows<T: Float>(a: &[T], b: &[T], out: &mut [T], n: usize, p: usize) {
+    for (a_row, out_row) in a.chunks_exact(n).zip(out.chunks_exact_mut(p)) {
+        for (o, b_row) in out_row.iter_mut().zip(b.chunks_exact(n)) {
+            *o = dot(a_row, b_row);
+        }
+    }
+}
+
+// out (k x m) += aᵀ * b, where a is rows x k, b is rows x m and `out` starts
+// zeroed
+pub fn transpose_multiply<T: Float>(a: &[T], b: &[T], out: &mut [T], k: usize, m: usize) {
+    if k == 0 || m == 0 {
+        return;
+    }
+    #[cfg(feature = "parallel")]
+    if a.len() * m >= PARALLEL_WORK {
+        // Each task owns a band of output rows and walks every input row
+        out.par_chunks_mut(BAND_ROWS * m)
+            .enumerate()
+            .for_each(|(band, out)| {
+                let first = band * BAND_ROWS;
+                for (a_row, b_row) in a.chunks_exact(k).zip(b.chunks_exact(m)) {
+                    for (out_row, &a) in out.chunks_exact_mut(m).zip(&a_row[first..]) {
+                        axpy(out_row, a, b_row);
+                    }
+                }
+            });
+        return;
+    }
+    for (a_row, b_row) in a.chunks_exact(k).zip(b.chunks_exact(m)) {
+        for (out_row, &a) in out.chunks_exact_mut(m).zip(a_row) {
+            axpy(out_row, a, b_row);
+        }
+    }
+}
+
+// a = f(a, b) element by element
+pub fn zip_in_place<T: Float, F>(a: &mut [T], b: &[T], f: F)
+where
+    F: Fn(T, T) -> T + Sync,
+{
+    #[cfg(feature = "parallel")]
+    if a.len() >= PARALLEL_WORK {
+        a.par_chunks_mut(BAND_ELEMENTS)
+            .zip(b.par_chunks(BAND_ELEMENTS))
+            .for_each(|(a, b)| zip(a, b, &f));
+        return;
+    }
+    zip(a, b, &f);
+}
+
+// Every row of `a` = f(row, `row`) element by element
+pub fn zip_rows_in_place<T: Float, F>(a: &mut [T], row: &[T], f: F)
+where
+    F: Fn(T, T) -> T + Sync,
+{
+    if row.is_empty() {
+        return;
+    }
+    #[cfg(feature = "parallel")]
+    if a.len() >= PARALLEL_WORK {
+        a.par_chunks_mut(row.len())
+            .for_each(|chunk| zip(chunk, row, &f));
+        return;
+    }
+    for chunk in a.chunks_exact_mut(row.len()) {
+        zip(chunk, row, &f);
+    }
+}
+
+// a = f(a) element by element
+pub fn map_in_place<T: Float, F>(a: &mut [T], f: F)
+where
+    F: Fn(T) -> T + Sync,
+{
+    #[cfg(feature = "parallel")]
+    if a.len() >= PARALLEL_WORK {
+        a.par_chunks_mut(BAND_ELEMENTS)
+            .for_each(|a| a.iter_mut().for_each(|x| *x = f(*x)));
+        return;
+    }
+    a.iter_mut().for_each(|x| *x = f(*x));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rng;
+    use rand::Rng;
+
+    fn random(len: usize, seed: u64) -> Vec<f64> {
+        let mut rng = rng::stream(seed, 0);
+        (0..len).map(|_| rng.gen::<f64>() * 2.0 - 1.0).collect()
+    }
+
+    fn assert_close(actual: &[f64], expected: &[f64], terms: usize) {
+        assert_eq!(actual.len(), expected.len());
+        for (i, (a, e)) in actual.iter().zip(expected).enumerate() {
+            let tolerance = 1e-15 * terms as f64 * (1.0 + e.abs());
+            assert!((a - e).abs() <= tolerance, "element {}: {} vs {}", i, a, e);
+        }
+    }
+
+    // Lengths around and between multiples of the lane count
+    #[test]
+    fn simd_loops_match_the_scalar_ones() {
+        for len in 0..3 * LANES + 3 {
+            let (a, b) = (random(len, 1), random(len, 2));
+
+            let mut expected = random(len, 3);
+            let mut actual = expected.clone();
+            scalar::axpy(&mut expected, 0.7, &b);
+            simd::axpy(&mut actual, 0.7, &b);
+            assert_eq!(actual, expected);
+
+            let f = |x: f64, y: f64| x * y - 0.5;
+            let mut expected = a.clone();
+            let mut actual = a.clone();
+            scalar::zip(&mut expected, &b, &f);
+            simd::zip(&mut actual, &b, &f);
+            assert_eq!(actual, expected);
+
+            assert_close(&[simd::dot(&a, &b)], &[scalar::dot(&a, &b)], len);
+        }
+    }
+
+    // Shapes both below and above the `parallel` threshold, with partial
+    // tiles and lanes
+    #[test]
+    fn products_match_the_textbook_loops() {
+        for (rows, n, m) in [(3, 5, 7), (1, 12, 64), (300, 70, 130), (257, 129, 9)] {
+            let a = random(rows * n, 4);
+            let b = random(n * m, 5);
+            let mut expected = vec![0.0; rows * m];
+            for i in 0..rows {
+                for j in 0..m {
+                    for k in 0..n {
+                        expected[i * m + j] += a[i * n + k] * b[k * m + j];
+                    }
+                }
+            }
+
+            let mut out = vec![0.0; rows * m];
+            multiply(&a, &b, &mut out, n, m);
+            assert_close(&out, &expected, n);
+
+            // The same product through a transposed right-hand side
+            let mut b_transposed = vec![0.0; m * n];
+            for k in 0..n {
+                for j in 0..m {
+                    b_transposed[j * n + k] = b[k * m + j];
+                }
+            }
+            multiply_transposed(&a, &b_transposed, &mut out, n, m);
+            assert_close(&out, &expected, n);
+
+            // ... and through a transposed left-hand side
+            let mut a_transposed = vec![0.0; n * rows];
+            for i in 0..rows {
+                for k in 0..n {
+                    a_transposed[k * rows + i] = a[i * n + k];
+                }
+            }
+            out.iter_mut().for_each(|o| *o = 0.0);
+            transpose_multiply(&a_transposed, &b, &mut out, rows, m);
+            assert_close(&out, &expected, n);
+        }
+    }
+
+    #[test]
+    fn element_wise_loops_match_the_textbook_loops() {
+        for len in [10, 100_003] {
+            let (a, b) = (random(len, 6), random(len, 7));
+
+            let mut actual = a.clone();
+            zip_in_place(&mut actual, &b, |x, y| x - 2.0 * y);
+            let expected: Vec<f64> = a.iter().zip(&b).map(|(x, y)| x - 2.0 * y).collect();
+            assert_eq!(actual, expected);
+
+            let mut actual = a.clone();
+            map_in_place(&mut actual, |x| x.max(0.0));
+            let expected: Vec<f64> = a.iter().map(|x| x.max(0.0)).collect();
+            assert_eq!(actual, expected);
+
+            let row = &b[..len / 10];
+            let mut actual = a[..row.len() * 10].to_vec();
+            zip_rows_in_place(&mut actual, row, |x, y| x + y);
+            let expected: Vec<f64> = (0..actual.len())
+                .map(|i| a[i] + row[i % row.len()])
+                .collect();
+            assert_eq!(actual, expected);
+        }
+    }
+}
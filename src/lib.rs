@@ -0,0 +1,14 @@
+pub mod agent;
+pub mod checkpoint;
+pub mod env;
+pub mod float;
+pub mod game;
+mod kernels;
+pub mod matrix;
+pub mod nn;
+pub mod optimizer;
+pub mod parallel;
+pub mod persist;
+pub mod replay;
+pub mod rng;
+pub mod vec_env;
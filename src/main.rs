@@ -1,27 +1,71 @@
-mod agent;
-mod game;
-mod nn;
+mod input;
 mod render;
 
-use agent::Agent;
-use game::{Game, GameStatus};
 use macroquad::prelude::*;
+use rusty_snake::agent::Agent;
+use rusty_snake::env::Environment;
+use rusty_snake::game::{Action, BoardConfig, Game, GameStatus};
+use rusty_snake::nn::NeuralNetwork;
+use rusty_snake::rng;
+use std::str::FromStr;
 
-#[macroquad::main("Rusty Snake")]
+// Value following `flag` on the command line, e.g. `--seed 42` or `--board 20x20`
+fn arg_value<T: FromStr>(flag: &str) -> Option<T> {
+    let args: Vec<String> = std::env::args().collect();
+    args.iter().position(|arg| arg == flag).map(|i| {
+        args.get(i + 1)
+            .and_then(|value| value.parse().ok())
+            .unwrap_or_else(|| panic!("invalid value for {}", flag))
+    })
+}
+
+// The window is sized for the board picked with `--board`
+fn window_conf() -> Conf {
+    let board: BoardConfig = arg_value("--board").unwrap_or_default();
+    let (window_width, window_height) = render::window_size(board);
+    Conf {
+        window_title: "Rusty Snake".to_owned(),
+        window_width,
+        window_height,
+        ..Default::default()
+    }
+}
+
+#[macroquad::main(window_conf)]
 async fn main() {
+    // `--seed N` makes a session reproducible; otherwise a random seed is used
+    let seed = arg_value("--seed").unwrap_or_else(rng::random_seed);
+    let board: BoardConfig = arg_value("--board").unwrap_or_default();
+    println!("Seed: {}", seed);
     let mut ai_controlled = true;
-    let mut game = Game::new();
-    let mut agent = Agent::new();
+    let mut game = Game::with_seed(board, seed);
+    let mut agent: Agent = Agent::with_seed(game.observation_size(), game.action_count(), seed);
+
+    // `--load PATH` resumes training from saved weights, `--watch PATH` just
+    // plays them greedily. S saves the current weights to `--save PATH`.
+    let load_path: Option<String> = arg_value("--load");
+    let watch_path: Option<String> = arg_value("--watch");
+    let save_path: String = arg_value("--save").unwrap_or_else(|| "snake.model".to_string());
+    let watching = watch_path.is_some();
+    if let Some(path) = watch_path.or(load_path) {
+        let network = NeuralNetwork::load(&path)
+            .unwrap_or_else(|err| panic!("failed to load {}: {}", path, err));
+        agent.set_network(network);
+    }
+    if watching {
+        agent.epsilon = 0.0;
+        agent.min_epsilon = 0.0;
+    }
     let mut last_update = get_time();
     let mut game_over_time: Option<f64> = None;
 
     loop {
         clear_background(BLACK);
-        game.draw_borders();
+        render::draw_borders(&game);
 
         match game.game_status {
             GameStatus::Start => {
-                game.start_game();
+                render::start_game(&game);
                 if is_key_pressed(KeyCode::Space) {
                     game.game_status = GameStatus::Running;
                 }
@@ -30,50 +74,49 @@ async fn main() {
                 if is_key_pressed(KeyCode::T) {
                     ai_controlled = !ai_controlled; // Toggle the AI control
                 }
-                game.handle_input();
-                game.draw();
-                game.score_counter();
-                game.high_score();
-                game.n_games();
+                if is_key_pressed(KeyCode::S) {
+                    match agent.neural_network.save(&save_path) {
+                        Ok(()) => println!("Saved model to {}", save_path),
+                        Err(err) => println!("Failed to save {}: {}", save_path, err),
+                    }
+                }
+                input::handle_input(&mut game);
+                render::draw(&game);
+                render::hud(&game);
                 if get_time() - last_update > game.speed {
-                    let current_state = game.get_game_state();
+                    let current_state = game.observation();
                     let mut action = agent.select_action(&current_state);
-                    if ai_controlled {
-                        game.handle_action(&action);
-                    } else {
-                        action = game.handle_input();
+                    if !ai_controlled {
+                        action = input::handle_input(&mut game).index();
                     }
-                    game.move_snake();
-                    game.collision_with_border();
-                    game.collision_with_self();
-
-                    let mut reward: f64 = 0.0;
-                    if !game.running {
-                        reward = -10.0;
-                        game.game_status = GameStatus::GameOver;
-                    } else if game.collision_with_food() {
-                        reward = 5.0;
-                        game.food = game.new_food();
-                    } else if game.time_starving > 10000 + (game.snake.len() as i32) * 10 {
-                        let calculated_value =
-                            (game.time_starving as f64 - 10000.0 - game.snake.len() as f64 * 10.0)
-                                * 0.01;
-                        reward = (-calculated_value as f64).max(-0.5);
-                    }
-                    //new state
-                    println!("Reward: {}", reward);
-                    let done = !game.running;
-                    let new_state = game.get_game_state();
-                    // train the agent
-                    println!("Action Taken :{}", action);
-                    agent.train(&current_state, action.clone(), reward, &new_state, done);
-                    agent.remember(&current_state, action, reward, &new_state, done);
+                    let outcome = Environment::step(&mut game, action);
+                    println!("Reward: {}", outcome.reward);
+                    println!("Action Taken :{}", Action::from_index(action));
                     last_update = get_time();
+                    if !watching {
+                        // train the agent
+                        agent.train(
+                            &current_state,
+                            action,
+                            outcome.reward,
+                            &outcome.observation,
+                            outcome.done,
+                        );
+                        agent.remember(
+                            &current_state,
+                            action,
+                            outcome.reward,
+                            &outcome.observation,
+                            outcome.done,
+                        );
+                    }
                 }
             }
             GameStatus::GameOver => {
-                game.game_over();
-                agent.train_long_memory();
+                render::game_over(&game);
+                if !watching {
+                    agent.train_long_memory();
+                }
                 if game_over_time.is_none() {
                     game_over_time = Some(get_time());
                 }
@@ -0,0 +1,875 @@
+// Dense row-major matrix of f64, or of f32 as `Matrix<f32>`. The allocating
+// operations (`add`, `multiply`, ..., and the operators: `&a * &b` is the
+// matrix product, `a * 2.0` scales and `+` also broadcasts a single row) are
+// convenient for one-off maths; the training loop uses the in-place and
+// `_into` variants, which reuse the storage they are given and so stop
+// allocating once their buffers have grown to size.
+//
+// Operations that need fitting shapes have `try_` versions returning a
+// `ShapeError`; the plain ones panic with its message.
+use crate::float::Float;
+use crate::kernels;
+use crate::persist::{self, invalid_data};
+use ::rand::Rng;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::ops::{Add, AddAssign, Index, IndexMut, Mul, MulAssign, Neg, Range, Sub, SubAssign};
+
+// Operands whose shapes do not fit an operation, e.g. a product of a 1x11
+// and a 12x64 matrix
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ShapeError {
+    pub operation: &'static str,
+    pub left: (usize, usize),
+    pub right: (usize, usize),
+}
+
+impl fmt::Display for ShapeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "shape mismatch in {}: {}x{} and {}x{}",
+            self.operation, self.left.0, self.left.1, self.right.0, self.right.1
+        )
+    }
+}
+
+impl std::error::Error for ShapeError {}
+
+// Largest matrix `read_from` accepts, far above any network here but small
+// enough that a corrupt header can't exhaust memory
+const MAX_STORED_ELEMENTS: usize = 1 << 28;
+
+// The panicking operations are these wrappers around the `try_` ones
+pub(crate) fn or_panic<T>(result: Result<T, ShapeError>) -> T {
+    result.unwrap_or_else(|error| panic!("{}", error))
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Matrix<T: Float = f64> {
+    // Element (i, j) is at i * cols + j
+    data: Vec<T>,
+    rows: usize,
+    cols: usize,
+}
+// This was such a waste of time
+impl<T: Float> Matrix<T> {
+    // Create a new matrix
+    pub fn new(rows: usize, cols: usize) -> Self {
+        Self {
+            data: vec![T::ZERO; rows * cols],
+            rows,
+            cols,
+        }
+    }
+
+    // A rows x cols matrix holding `data` row after row
+    pub fn from_vec(rows: usize, cols: usize, data: Vec<T>) -> Self {
+        assert_eq!(
+            data.len(),
+            rows * cols,
+            "{} values do not fill a {}x{} matrix",
+            data.len(),
+            rows,
+            cols
+        );
+        Self { data, rows, cols }
+    }
+
+    pub fn rows(&self) -> usize {
+        self.rows
+    }
+
+    pub fn cols(&self) -> usize {
+        self.cols
+    }
+
+    // (rows, cols)
+    pub fn shape(&self) -> (usize, usize) {
+        (self.rows, self.cols)
+    }
+
+    // Same as `matrix[i]`
+    pub fn row(&self, i: usize) -> &[T] {
+        &self[i]
+    }
+
+    pub fn row_mut(&mut self, i: usize) -> &mut [T] {
+        &mut self[i]
+    }
+
+    // Column j, top to bottom
+    pub fn column(&self, j: usize) -> Vec<T> {
+        assert!(j < self.cols, "column {} of {}", j, self.cols);
+        self.rows_iter().map(|row| row[j]).collect()
+    }
+
+    // The rows in `range` as a new matrix
+    pub fn slice_rows(&self, range: Range<usize>) -> Matrix<T> {
+        assert!(
+            range.start <= range.end && range.end <= self.rows,
+            "rows {:?} of {}",
+            range,
+            self.rows
+        );
+        let data = self.data[range.start * self.cols..range.end * self.cols].to_vec();
+        Matrix::from_vec(range.len(), self.cols, data)
+    }
+
+    // The columns in `range` as a new matrix
+    pub fn slice_cols(&self, range: Range<usize>) -> Matrix<T> {
+        assert!(
+            range.start <= range.end && range.end <= self.cols,
+            "columns {:?} of {}",
+            range,
+            self.cols
+        );
+        let mut data = Vec::with_capacity(self.rows * range.len());
+        for row in self.rows_iter() {
+            data.extend_from_slice(&row[range.clone()]);
+        }
+        Matrix::from_vec(self.rows, range.len(), data)
+    }
+
+    // Every element, row after row
+    pub fn as_slice(&self) -> &[T] {
+        &self.data
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [T] {
+        &mut self.data
+    }
+
+    // Reshapes to rows x cols filled with zeros, keeping the allocation when
+    // it is large enough
+    pub fn resize(&mut self, rows: usize, cols: usize) {
+        self.data.clear();
+        self.data.resize(rows * cols, T::ZERO);
+        self.rows = rows;
+        self.cols = cols;
+    }
+
+    // Becomes a copy of `other`, reusing this matrix's storage
+    pub fn copy_from(&mut self, other: &Matrix<T>) {
+        self.data.clear();
+        self.data.extend_from_slice(&other.data);
+        self.rows = other.rows;
+        self.cols = other.cols;
+    }
+
+    pub fn print(&self) {
+        print!("{}", self);
+    }
+
+    fn rows_iter(&self) -> impl Iterator<Item = &[T]> {
+        // `max(1)` keeps `chunks` happy for matrices without columns
+        self.data.chunks(self.cols.max(1)).take(self.rows)
+    }
+
+    fn check(
+        &self,
+        other: &Matrix<T>,
+        operation: &'static str,
+        fits: bool,
+    ) -> Result<(), ShapeError> {
+        if fits {
+            Ok(())
+        } else {
+            Err(ShapeError {
+                operation,
+                left: self.shape(),
+                right: other.shape(),
+            })
+        }
+    }
+
+    fn check_same_shape(
+        &self,
+        other: &Matrix<T>,
+        operation: &'static str,
+    ) -> Result<(), ShapeError> {
+        self.check(other, operation, self.shape() == other.shape())
+    }
+
+    // Element-wise addition
+    pub fn add(&self, other: &Matrix<T>) -> Matrix<T> {
+        or_panic(self.try_add(other))
+    }
+
+    pub fn try_add(&self, other: &Matrix<T>) -> Result<Matrix<T>, ShapeError> {
+        self.check_same_shape(other, "addition")?;
+        let mut result = self.clone();
+        result.add_in_place(other);
+        Ok(result)
+    }
+
+    pub fn add_in_place(&mut self, other: &Matrix<T>) {
+        or_panic(self.try_add_in_place(other))
+    }
+
+    pub fn try_add_in_place(&mut self, other: &Matrix<T>) -> Result<(), ShapeError> {
+        self.check_same_shape(other, "addition")?;
+        kernels::zip_in_place(&mut self.data, &other.data, |a, b| a + b);
+        Ok(())
+    }
+
+    // Adds `row` (1 x cols) to every row, e.g. a bias to a batch of outputs
+    pub fn add_row(&self, row: &Matrix<T>) -> Matrix<T> {
+        or_panic(self.try_add_row(row))
+    }
+
+    pub fn try_add_row(&self, row: &Matrix<T>) -> Result<Matrix<T>, ShapeError> {
+        self.check_row(row)?;
+        let mut result = self.clone();
+        result.add_row_in_place(row);
+        Ok(result)
+    }
+
+    fn check_row(&self, row: &Matrix<T>) -> Result<(), ShapeError> {
+        let fits = row.rows == 1 && row.cols == self.cols;
+        self.check(row, "row broadcast", fits)
+    }
+
+    pub fn add_row_in_place(&mut self, row: &Matrix<T>) {
+        or_panic(self.try_add_row_in_place(row))
+    }
+
+    pub fn try_add_row_in_place(&mut self, row: &Matrix<T>) -> Result<(), ShapeError> {
+        self.check_row(row)?;
+        kernels::zip_rows_in_place(&mut self.data, &row.data, |a, b| a + b);
+        Ok(())
+    }
+
+    // Element-wise subtraction
+    pub fn subtract(&self, other: &Matrix<T>) -> Matrix<T> {
+        or_panic(self.try_subtract(other))
+    }
+
+    pub fn try_subtract(&self, other: &Matrix<T>) -> Result<Matrix<T>, ShapeError> {
+        self.check_same_shape(other, "subtraction")?;
+        let mut result = self.clone();
+        result.subtract_in_place(other);
+        Ok(result)
+    }
+
+    pub fn subtract_in_place(&mut self, other: &Matrix<T>) {
+        or_panic(self.try_subtract_in_place(other))
+    }
+
+    pub fn try_subtract_in_place(&mut self, other: &Matrix<T>) -> Result<(), ShapeError> {
+        self.check_same_shape(other, "subtraction")?;
+        kernels::zip_in_place(&mut self.data, &other.data, |a, b| a - b);
+        Ok(())
+    }
+
+    // Matrix multiplication
+    pub fn multiply(&self, other: &Matrix<T>) -> Matrix<T> {
+        or_panic(self.try_multiply(other))
+    }
+
+    // Fails unless the columns of `self` match the rows of `other`
+    pub fn try_multiply(&self, other: &Matrix<T>) -> Result<Matrix<T>, ShapeError> {
+        let mut result = Matrix::default();
+        self.try_multiply_into(other, &mut result)?;
+        Ok(result)
+    }
+
+    // self * other, written to `out`. Runs i-k-j over 64 x 64 tiles of
+    // `other` so the inner loop streams along contiguous rows; each element
+    // still sums its products in k order, exactly like the textbook loop.
+    // The `parallel` feature splits large products by rows.
+    pub fn multiply_into(&self, other: &Matrix<T>, out: &mut Matrix<T>) {
+        or_panic(self.try_multiply_into(other, out))
+    }
+
+    pub fn try_multiply_into(
+        &self,
+        other: &Matrix<T>,
+        out: &mut Matrix<T>,
+    ) -> Result<(), ShapeError> {
+        self.check(other, "matrix product", self.cols == other.rows)?;
+        out.resize(self.rows, other.cols);
+        kernels::multiply(
+            &self.data,
+            &other.data,
+            &mut out.data,
+            self.cols,
+            other.cols,
+        );
+        Ok(())
+    }
+
+    // self * otherᵀ without transposing: both operands are read along their
+    // rows, e.g. the error sent back through a layer's weights
+    pub fn multiply_transposed_into(&self, other: &Matrix<T>, out: &mut Matrix<T>) {
+        or_panic(self.try_multiply_transposed_into(other, out))
+    }
+
+    pub fn try_multiply_transposed_into(
+        &self,
+        other: &Matrix<T>,
+        out: &mut Matrix<T>,
+    ) -> Result<(), ShapeError> {
+        let fits = self.cols == other.cols;
+        self.check(other, "product with a transpose", fits)?;
+        out.resize(self.rows, other.rows);
+        kernels::multiply_transposed(
+            &self.data,
+            &other.data,
+            &mut out.data,
+            self.cols,
+            other.rows,
+        );
+        Ok(())
+    }
+
+    // selfᵀ * other without transposing, e.g. a layer's weight gradient
+    // from its input rows and deltas
+    pub fn transpose_multiply_into(&self, other: &Matrix<T>, out: &mut Matrix<T>) {
+        or_panic(self.try_transpose_multiply_into(other, out))
+    }
+
+    pub fn try_transpose_multiply_into(
+        &self,
+        other: &Matrix<T>,
+        out: &mut Matrix<T>,
+    ) -> Result<(), ShapeError> {
+        let fits = self.rows == other.rows;
+        self.check(other, "product of a transpose", fits)?;
+        out.resize(self.cols, other.cols);
+        kernels::transpose_multiply(
+            &self.data,
+            &other.data,
+            &mut out.data,
+            self.cols,
+            other.cols,
+        );
+        Ok(())
+    }
+
+    // Element-wise (Hadamard) multiplication
+    pub fn hadamard(&self, other: &Matrix<T>) -> Matrix<T> {
+        or_panic(self.try_hadamard(other))
+    }
+
+    pub fn try_hadamard(&self, other: &Matrix<T>) -> Result<Matrix<T>, ShapeError> {
+        self.check_same_shape(other, "hadamard product")?;
+        let mut result = self.clone();
+        result.hadamard_in_place(other);
+        Ok(result)
+    }
+
+    pub fn hadamard_in_place(&mut self, other: &Matrix<T>) {
+        or_panic(self.try_hadamard_in_place(other))
+    }
+
+    pub fn try_hadamard_in_place(&mut self, other: &Matrix<T>) -> Result<(), ShapeError> {
+        self.check_same_shape(other, "hadamard product")?;
+        kernels::zip_in_place(&mut self.data, &other.data, |a, b| a * b);
+        Ok(())
+    }
+
+    // Scalar multiplication
+    pub fn scalar_multiply(&self, scalar: T) -> Matrix<T> {
+        let mut result = self.clone();
+        result.scale_in_place(scalar);
+        result
+    }
+
+    pub fn scale_in_place(&mut self, scalar: T) {
+        kernels::map_in_place(&mut self.data, |a| a * scalar);
+    }
+
+    // Column sums as a 1 x cols row, e.g. a bias gradient over a batch
+    pub fn sum_rows_into(&self, out: &mut Matrix<T>) {
+        out.resize(1, self.cols);
+        for row in self.rows_iter() {
+            for (o, &a) in out.data.iter_mut().zip(row) {
+                *o += a;
+            }
+        }
+    }
+
+    // Column sums as a 1 x cols row
+    pub fn sum_rows(&self) -> Matrix<T> {
+        let mut result = Matrix::default();
+        self.sum_rows_into(&mut result);
+        result
+    }
+
+    // Column of the largest value in each row (the last one on ties), e.g.
+    // the greedy action for each row of Q-values
+    pub fn argmax(&self) -> Vec<usize> {
+        let mut result = Vec::new();
+        self.argmax_into(&mut result);
+        result
+    }
+
+    // Compared by `total_cmp`, so NaNs never panic: a positive NaN wins over
+    // every number and a negative one loses to every number
+    pub fn argmax_into(&self, out: &mut Vec<usize>) {
+        out.clear();
+        out.extend((0..self.rows).map(|i| {
+            self[i]
+                .iter()
+                .enumerate()
+                .max_by(|a, b| a.1.total_cmp(b.1))
+                .map_or(0, |(j, _)| j)
+        }));
+    }
+
+    // Transpose
+    pub fn transpose(&self) -> Matrix<T> {
+        let mut result = Matrix::default();
+        self.transpose_into(&mut result);
+        result
+    }
+
+    pub fn transpose_into(&self, out: &mut Matrix<T>) {
+        out.resize(self.cols, self.rows);
+        for (i, row) in self.rows_iter().enumerate() {
+            for (j, &value) in row.iter().enumerate() {
+                out.data[j * self.rows + i] = value;
+            }
+        }
+    }
+
+    // Apply a function element-wise to the matrix
+    pub fn apply<F>(&self, func: F) -> Matrix<T>
+    where
+        F: Fn(T) -> T,
+    {
+        let mut result = self.clone();
+        result.apply_in_place(func);
+        result
+    }
+
+    pub fn apply_in_place<F>(&mut self, func: F)
+    where
+        F: Fn(T) -> T,
+    {
+        self.data.iter_mut().for_each(|a| *a = func(*a));
+    }
+
+    pub fn from_array_to_row(data: &[T]) -> Self {
+        Self {
+            data: data.to_vec(),
+            rows: 1,
+            cols: data.len(),
+        }
+    }
+
+    pub fn from_array_to_column(data: &[T]) -> Self {
+        Self {
+            data: data.to_vec(),
+            rows: data.len(),
+            cols: 1,
+        }
+    }
+
+    // Uniform in [min, max). Values are drawn as f64 and then rounded, so an
+    // f32 matrix holds the same numbers as the f64 one from the same RNG.
+    pub fn random<R: Rng>(rows: usize, cols: usize, min: f64, max: f64, rng: &mut R) -> Self {
+        let data = (0..rows * cols)
+            .map(|_| T::from_f64(rng.gen::<f64>() * (max - min) + min))
+            .collect();
+        Self { data, rows, cols }
+    }
+
+    // Rows, columns, then the values in `T`'s own width
+    pub fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        persist::write_usize(w, self.rows)?;
+        persist::write_usize(w, self.cols)?;
+        for &value in &self.data {
+            value.write_to(w)?;
+        }
+        Ok(())
+    }
+
+    // The shape comes from the file, so the storage grows with the values
+    // actually read rather than being allocated from it up front
+    pub fn read_from<R: Read>(r: &mut R) -> io::Result<Matrix<T>> {
+        let rows = persist::read_usize(r)?;
+        let cols = persist::read_usize(r)?;
+        let len = rows
+            .checked_mul(cols)
+            .filter(|&len| len <= MAX_STORED_ELEMENTS)
+            .ok_or_else(|| invalid_data(format!("invalid matrix shape {}x{}", rows, cols)))?;
+        let mut data = Vec::with_capacity(len.min(4096));
+        for _ in 0..len {
+            data.push(T::read_from(r)?);
+        }
+        Ok(Matrix { data, rows, cols })
+    }
+
+    pub fn norm(&self) -> T {
+        let mut sum = T::ZERO;
+        for &value in &self.data {
+            sum += value * value;
+        }
+        sum.sqrt()
+    }
+
+    // The same matrix in another precision, e.g. f32 weights widened for a
+    // gradient check; rounds to nearest when narrowing
+    pub fn cast<U: Float>(&self) -> Matrix<U> {
+        Matrix {
+            data: self.data.iter().map(|&x| U::from_f64(x.to_f64())).collect(),
+            rows: self.rows,
+            cols: self.cols,
+        }
+    }
+}
+
+// `matrix[i]` is row i, so `matrix[i][j]` is element (i, j)
+impl<T: Float> Index<usize> for Matrix<T> {
+    type Output = [T];
+
+    fn index(&self, index: usize) -> &Self::Output {
+        assert!(index < self.rows, "row {} of {}", index, self.rows);
+        &self.data[index * self.cols..(index + 1) * self.cols]
+    }
+}
+
+impl<T: Float> IndexMut<usize> for Matrix<T> {
+    fn index_mut(&mut self, index: usize) -> &mut Self::Output {
+        assert!(index < self.rows, "row {} of {}", index, self.rows);
+        &mut self.data[index * self.cols..(index + 1) * self.cols]
+    }
+}
+
+// `matrix[(i, j)]` is element (i, j)
+impl<T: Float> Index<(usize, usize)> for Matrix<T> {
+    type Output = T;
+
+    fn index(&self, (i, j): (usize, usize)) -> &Self::Output {
+        &self[i][j]
+    }
+}
+
+impl<T: Float> IndexMut<(usize, usize)> for Matrix<T> {
+    fn index_mut(&mut self, (i, j): (usize, usize)) -> &mut Self::Output {
+        &mut self[i][j]
+    }
+}
+
+// One line per row, each column padded to its widest value
+impl<T: Float> fmt::Display for Matrix<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Calculate maximum width for each column
+        let max_widths: Vec<usize> = (0..self.cols)
+            .map(|j| {
+                self.rows_iter()
+                    .map(|row| format!("{:.3}", row[j]).len())
+                    .max()
+                    .unwrap_or(0)
+            })
+            .collect();
+
+        // Print each value formatted to the max width of its column
+        for row in self.rows_iter() {
+            for (value, width) in row.iter().zip(&max_widths) {
+                write!(f, "{:width$.3} ", value, width = width)?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+// `+` and `+=` take a matrix of the same shape, or broadcast a single row
+// (1 x cols) over every row the way a bias is added to a batch
+impl<T: Float> AddAssign<&Matrix<T>> for Matrix<T> {
+    fn add_assign(&mut self, other: &Matrix<T>) {
+        if other.rows == 1 && self.rows != 1 {
+            self.add_row_in_place(other);
+        } else {
+            self.add_in_place(other);
+        }
+    }
+}
+
+impl<T: Float> Add<&Matrix<T>> for Matrix<T> {
+    type Output = Matrix<T>;
+
+    fn add(mut self, other: &Matrix<T>) -> Matrix<T> {
+        self += other;
+        self
+    }
+}
+
+impl<T: Float> Add<&Matrix<T>> for &Matrix<T> {
+    type Output = Matrix<T>;
+
+    fn add(self, other: &Matrix<T>) -> Matrix<T> {
+        self.clone() + other
+    }
+}
+
+impl<T: Float> SubAssign<&Matrix<T>> for Matrix<T> {
+    fn sub_assign(&mut self, other: &Matrix<T>) {
+        self.subtract_in_place(other);
+    }
+}
+
+impl<T: Float> Sub<&Matrix<T>> for Matrix<T> {
+    type Output = Matrix<T>;
+
+    fn sub(mut self, other: &Matrix<T>) -> Matrix<T> {
+        self -= other;
+        self
+    }
+}
+
+impl<T: Float> Sub<&Matrix<T>> for &Matrix<T> {
+    type Output = Matrix<T>;
+
+    fn sub(self, other: &Matrix<T>) -> Matrix<T> {
+        self.clone() - other
+    }
+}
+
+// Matrix product
+impl<T: Float> Mul<&Matrix<T>> for &Matrix<T> {
+    type Output = Matrix<T>;
+
+    fn mul(self, other: &Matrix<T>) -> Matrix<T> {
+        self.multiply(other)
+    }
+}
+
+impl<T: Float> Mul<&Matrix<T>> for Matrix<T> {
+    type Output = Matrix<T>;
+
+    fn mul(self, other: &Matrix<T>) -> Matrix<T> {
+        self.multiply(other)
+    }
+}
+
+impl<T: Float> MulAssign<T> for Matrix<T> {
+    fn mul_assign(&mut self, scalar: T) {
+        self.scale_in_place(scalar);
+    }
+}
+
+impl<T: Float> Mul<T> for Matrix<T> {
+    type Output = Matrix<T>;
+
+    fn mul(mut self, scalar: T) -> Matrix<T> {
+        self *= scalar;
+        self
+    }
+}
+
+impl<T: Float> Mul<T> for &Matrix<T> {
+    type Output = Matrix<T>;
+
+    fn mul(self, scalar: T) -> Matrix<T> {
+        self.clone() * scalar
+    }
+}
+
+// `2.0 * &matrix`; a generic `impl Mul<&Matrix<T>> for T` is not allowed, so
+// one per float type
+macro_rules! impl_scalar_times_matrix {
+    ($($float:ty),*) => {$(
+        impl Mul<&Matrix<$float>> for $float {
+            type Output = Matrix<$float>;
+
+            fn mul(self, matrix: &Matrix<$float>) -> Matrix<$float> {
+                matrix * self
+            }
+        }
+    )*};
+}
+
+impl_scalar_times_matrix!(f32, f64);
+
+impl<T: Float> Neg for Matrix<T> {
+    type Output = Matrix<T>;
+
+    fn neg(mut self) -> Matrix<T> {
+        self.apply_in_place(|x| -x);
+        self
+    }
+}
+
+impl<T: Float> Neg for &Matrix<T> {
+    type Output = Matrix<T>;
+
+    fn neg(self) -> Matrix<T> {
+        -self.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rng;
+
+    fn textbook_product(a: &Matrix, b: &Matrix) -> Matrix {
+        let mut result = Matrix::new(a.rows, b.cols);
+        for i in 0..a.rows {
+            for j in 0..b.cols {
+                for k in 0..a.cols {
+                    result[(i, j)] += a[(i, k)] * b[(k, j)];
+                }
+            }
+        }
+        result
+    }
+
+    // Sizes that leave partial tiles on every side of the 64 x 64 blocks
+    #[test]
+    fn products_match_the_textbook_loop() {
+        let mut rng = rng::stream(1, 0);
+        let a = Matrix::random(5, 130, -1.0, 1.0, &mut rng);
+        let b = Matrix::random(130, 70, -1.0, 1.0, &mut rng);
+        let expected = textbook_product(&a, &b);
+
+        assert_eq!(&a * &b, expected);
+        let mut out = Matrix::new(2, 2);
+        a.transpose().transpose_multiply_into(&b, &mut out);
+        assert_eq!(out, expected);
+        a.multiply_transposed_into(&b.transpose(), &mut out);
+        if cfg!(feature = "simd") {
+            // Dot products add up eight partial sums
+            let close = |(x, y): (&f64, &f64)| (x - y).abs() < 1e-12;
+            assert!(out.as_slice().iter().zip(expected.as_slice()).all(close));
+        } else {
+            assert_eq!(out, expected);
+        }
+    }
+
+    #[test]
+    fn operators_work_element_wise_and_broadcast_rows() {
+        let a = Matrix::from_vec(2, 3, vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0]);
+        let bias = Matrix::from_vec(1, 3, vec![10.0, 20.0, 30.0]);
+
+        assert_eq!(
+            &a + &bias,
+            Matrix::from_vec(2, 3, vec![11.0, 22.0, 33.0, 14.0, 25.0, 36.0])
+        );
+        assert_eq!(&(&a + &a) - &a, a);
+        assert_eq!(-&a, &a * -1.0);
+        assert_eq!(2.0 * &a, &a + &a);
+
+        let mut b = a.clone();
+        b += &a;
+        b *= 0.5;
+        assert_eq!(b, a);
+        b[(1, 2)] = 0.0;
+        assert_eq!(b[1], [4.0, 5.0, 0.0]);
+    }
+
+    #[test]
+    fn slices_sums_and_argmax() {
+        let a = Matrix::from_vec(3, 2, vec![1.0, 7.0, 9.0, 2.0, 3.0, 3.0]);
+
+        assert_eq!(a.shape(), (3, 2));
+        assert_eq!(a.row(1), [9.0, 2.0]);
+        assert_eq!(a.column(1), vec![7.0, 2.0, 3.0]);
+        assert_eq!(
+            a.slice_rows(1..3),
+            Matrix::from_vec(2, 2, vec![9.0, 2.0, 3.0, 3.0])
+        );
+        assert_eq!(
+            a.slice_cols(0..1),
+            Matrix::from_array_to_column(&[1.0, 9.0, 3.0])
+        );
+        assert_eq!(a.sum_rows(), Matrix::from_array_to_row(&[13.0, 12.0]));
+        assert_eq!(a.argmax(), vec![1, 0, 1]);
+        let q_values = Matrix::from_vec(2, 3, vec![f64::NAN, 1.0, 2.0, 0.5, -f64::NAN, 0.25]);
+        assert_eq!(q_values.argmax(), vec![0, 0]);
+    }
+
+    #[test]
+    fn mismatched_shapes_are_reported_with_both_shapes() {
+        let a: Matrix = Matrix::new(1, 11);
+        let b = Matrix::new(12, 64);
+        let error = a.try_multiply(&b).unwrap_err();
+        assert_eq!(
+            error,
+            ShapeError {
+                operation: "matrix product",
+                left: (1, 11),
+                right: (12, 64),
+            }
+        );
+        assert_eq!(
+            error.to_string(),
+            "shape mismatch in matrix product: 1x11 and 12x64"
+        );
+        assert!(a.try_add(&b).is_err());
+        assert!(a.try_add_row(&Matrix::new(1, 12)).is_err());
+        assert!(b.try_subtract(&Matrix::new(12, 63)).is_err());
+        assert!(b.try_hadamard(&b).is_ok());
+
+        let mut out = Matrix::new(1, 1);
+        assert!(a.try_multiply_transposed_into(&b, &mut out).is_err());
+        assert!(a.try_transpose_multiply_into(&b, &mut out).is_err());
+        assert_eq!(out.shape(), (1, 1));
+        assert!(b.try_transpose_multiply_into(&b, &mut out).is_ok());
+        assert_eq!(out.shape(), (64, 64));
+        let mut c = b.clone();
+        assert!(c.try_add_in_place(&a).is_err());
+        assert!(c.try_subtract_in_place(&a).is_err());
+        assert!(c.try_hadamard_in_place(&a).is_err());
+        assert!(c.try_add_row_in_place(&a).is_err());
+        assert_eq!(c, b);
+        assert!(c.try_add_row_in_place(&Matrix::new(1, 64)).is_ok());
+    }
+
+    #[test]
+    fn stored_matrices_round_trip_and_bad_shapes_are_rejected() {
+        let a: Matrix = Matrix::random(3, 5, -1.0, 1.0, &mut rng::stream(3, 0));
+        let mut bytes = Vec::new();
+        a.write_to(&mut bytes).unwrap();
+        assert_eq!(Matrix::read_from(&mut bytes.as_slice()).unwrap(), a);
+
+        let header = |rows: u64, cols: u64| {
+            let mut bytes = Vec::new();
+            persist::write_u64(&mut bytes, rows).unwrap();
+            persist::write_u64(&mut bytes, cols).unwrap();
+            bytes
+        };
+        for (rows, cols) in [(1 << 20, 1 << 20), (1 << 33, 1 << 33), (u64::MAX, 2)] {
+            let error = Matrix::<f64>::read_from(&mut header(rows, cols).as_slice()).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        }
+        // A plausible shape with the values missing just runs out of data
+        let error = Matrix::<f32>::read_from(&mut header(1000, 1000).as_slice()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    #[should_panic(expected = "shape mismatch in addition: 2x2 and 2x3")]
+    fn panicking_operations_use_the_same_message() {
+        let _ = Matrix::<f64>::new(2, 2).add(&Matrix::new(2, 3));
+    }
+
+    // An f32 matrix holds the rounded values of the f64 one drawn from the
+    // same RNG, and its products stay within f32 rounding
+    #[test]
+    fn f32_matrices_round_the_f64_ones() {
+        let a: Matrix = Matrix::random(7, 20, -1.0, 1.0, &mut rng::stream(2, 0));
+        let single: Matrix<f32> = Matrix::random(7, 20, -1.0, 1.0, &mut rng::stream(2, 0));
+        assert_eq!(a.cast::<f32>(), single);
+        assert_eq!(single.cast::<f64>().cast::<f32>(), single);
+
+        let product = (&single * &single.transpose()).cast::<f64>();
+        let expected = &a * &a.transpose();
+        let close = |(x, y): (&f64, &f64)| (x - y).abs() < 1e-5;
+        assert!(product
+            .as_slice()
+            .iter()
+            .zip(expected.as_slice())
+            .all(close));
+    }
+
+    #[test]
+    fn display_pads_every_column_to_its_widest_value() {
+        let a = Matrix::from_vec(2, 2, vec![1.0, -20.5, 300.0, 4.0]);
+        assert_eq!(a.to_string(), "  1.000 -20.500 \n300.000   4.000 \n");
+    }
+}
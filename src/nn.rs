@@ -1,326 +1,1345 @@
+pub use crate::float::{Float, Precision};
+pub use crate::matrix::Matrix;
+use crate::matrix::{or_panic, ShapeError};
+use crate::optimizer::{Optimizer, OptimizerConfig};
+use crate::persist::{self, invalid_data};
 use ::rand::Rng;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::marker::PhantomData;
+use std::path::Path;
 
-#[derive(Clone)]
-pub struct Matrix {
-    data: Vec<Vec<f64>>,
-    rows: usize,
-    cols: usize,
+// Model file layout, all little-endian:
+//   b"RSNN", format version (u32), `Precision` tag (u8), layer count (u64),
+//   then per layer: weights, biases (each rows, cols, row-major values)
+//   and the `Activation` tag (u8) with its parameter (f64);
+//   a dueling flag (u8), followed by the value and advantage stream layers
+//   when it is set;
+//   the optimizer (kind u8 and its hyperparameters), the gradient clip (flag
+//   u8, norm f64), the update count (u64) and the optimizer state of every
+//   layer, trunk first: state matrix count (u64), then the weight state
+//   matrices and the bias state matrices.
+// Every matrix value is stored as an f32 or an f64 according to the
+// precision tag.
+const MODEL_MAGIC: &[u8; 4] = b"RSNN";
+const MODEL_VERSION: u32 = 1;
+
+// Precision a model was saved in, from the start of the file alone
+pub fn read_precision<R: Read>(r: &mut R) -> io::Result<Precision> {
+    let version = persist::read_magic(r, MODEL_MAGIC)?;
+    persist::check_version("model", version, MODEL_VERSION)?;
+    Precision::read_from(r)
 }
-// This was such a waste of time
-impl Matrix {
-    // Create a new matrix
-    pub fn new(rows: usize, cols: usize) -> Self {
-        Self {
-            data: vec![vec![0.0; cols]; rows],
-            rows,
-            cols,
-        }
-    }
 
-    pub fn print(&self) {
-        // Calculate maximum width for each column
-        let mut max_widths: Vec<usize> = vec![0; self.cols];
-        for j in 0..self.cols {
-            let mut max_width = 0;
-            for i in 0..self.rows {
-                let width = format!("{:.3}", self.data[i][j]).len();
-                if width > max_width {
-                    max_width = width;
+// Non-linearity applied to a layer's output, element-wise
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Activation {
+    Relu,
+    // Slope for negative inputs
+    LeakyRelu(f64),
+    Tanh,
+    Sigmoid,
+    // alpha * (e^x - 1) for negative inputs
+    Elu(f64),
+    Linear,
+}
+
+impl Activation {
+    pub fn apply<T: Float>(&self, x: T) -> T {
+        match *self {
+            Activation::Relu => x.max(T::ZERO),
+            Activation::LeakyRelu(slope) => {
+                if x > T::ZERO {
+                    x
+                } else {
+                    T::from_f64(slope) * x
+                }
+            }
+            Activation::Tanh => x.tanh(),
+            Activation::Sigmoid => T::ONE / (T::ONE + (-x).exp()),
+            Activation::Elu(alpha) => {
+                if x > T::ZERO {
+                    x
+                } else {
+                    T::from_f64(alpha) * x.exp_m1()
                 }
             }
-            max_widths[j] = max_width;
+            Activation::Linear => x,
         }
+    }
 
-        // Print each value formatted to the max width of its column
-        for i in 0..self.rows {
-            for j in 0..self.cols {
-                print!("{:width$.3} ", self.data[i][j], width = max_widths[j]);
+    // Derivative with respect to the input `x`
+    pub fn derivative<T: Float>(&self, x: T) -> T {
+        match *self {
+            Activation::Relu => {
+                if x > T::ZERO {
+                    T::ONE
+                } else {
+                    T::ZERO
+                }
+            }
+            Activation::LeakyRelu(slope) => {
+                if x > T::ZERO {
+                    T::ONE
+                } else {
+                    T::from_f64(slope)
+                }
             }
-            println!();
+            Activation::Tanh => T::ONE - x.tanh().powi(2),
+            Activation::Sigmoid => {
+                let s = self.apply(x);
+                s * (T::ONE - s)
+            }
+            Activation::Elu(alpha) => {
+                if x > T::ZERO {
+                    T::ONE
+                } else {
+                    T::from_f64(alpha) * x.exp()
+                }
+            }
+            Activation::Linear => T::ONE,
         }
     }
 
-    // Element-wise addition
-    pub fn add(&self, other: &Matrix) -> Matrix {
-        assert_eq!(self.rows, other.rows);
-        assert_eq!(self.cols, other.cols);
-        let mut result = Matrix::new(self.rows, self.cols);
-        for i in 0..self.rows {
-            for j in 0..self.cols {
-                result.data[i][j] = self.data[i][j] + other.data[i][j];
-            }
+    fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        let (tag, parameter) = match *self {
+            Activation::Linear => (0, 0.0),
+            Activation::LeakyRelu(slope) => (1, slope),
+            Activation::Relu => (2, 0.0),
+            Activation::Tanh => (3, 0.0),
+            Activation::Sigmoid => (4, 0.0),
+            Activation::Elu(alpha) => (5, alpha),
+        };
+        persist::write_u8(w, tag)?;
+        persist::write_f64(w, parameter)
+    }
+
+    fn read_from<R: Read>(r: &mut R) -> io::Result<Activation> {
+        let tag = persist::read_u8(r)?;
+        let parameter = persist::read_f64(r)?;
+        Ok(match tag {
+            0 => Activation::Linear,
+            1 => Activation::LeakyRelu(parameter),
+            2 => Activation::Relu,
+            3 => Activation::Tanh,
+            4 => Activation::Sigmoid,
+            5 => Activation::Elu(parameter),
+            other => return Err(invalid_data(format!("invalid activation {}", other))),
+        })
+    }
+}
+
+// How a backward pass changes the parameters
+#[derive(Clone, Copy)]
+struct UpdateRule<'a, T: Float> {
+    optimizer: &'a dyn Optimizer<T>,
+    // Number of this update, from 1
+    step: u64,
+    // Gradients longer than this are scaled down to it
+    gradient_clip: Option<f64>,
+}
+
+#[derive(Clone)]
+pub struct Layer<T: Float = f64> {
+    weights: Matrix<T>,
+    biases: Matrix<T>,
+    activation: Activation,
+    // Optimizer state per parameter matrix, empty until the first update
+    weights_state: Vec<Matrix<T>>,
+    biases_state: Vec<Matrix<T>>,
+}
+
+impl<T: Float> Layer<T> {
+    pub fn new<R: Rng>(
+        input_size: usize,
+        output_size: usize,
+        activation: Activation,
+        rng: &mut R,
+    ) -> Self {
+        // Randomly initialize weights and biases with small values.
+        let variance = 2.0 / input_size as f64;
+        let std_dev = variance.sqrt();
+
+        let weights = Matrix::random(input_size, output_size, -std_dev, std_dev, rng);
+        let biases = Matrix::new(1, output_size);
+        Self {
+            weights,
+            biases,
+            activation,
+            weights_state: Vec::new(),
+            biases_state: Vec::new(),
         }
-        result
     }
 
-    // Element-wise subtraction
-    pub fn subtract(&self, other: &Matrix) -> Matrix {
-        assert_eq!(self.rows, other.rows);
-        assert_eq!(self.cols, other.cols);
-        let mut result = Matrix::new(self.rows, self.cols);
-        for i in 0..self.rows {
-            for j in 0..self.cols {
-                result.data[i][j] = self.data[i][j] - other.data[i][j];
-            }
+    fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        self.weights.write_to(w)?;
+        self.biases.write_to(w)?;
+        self.activation.write_to(w)
+    }
+
+    fn read_from<R: Read>(r: &mut R) -> io::Result<Layer<T>> {
+        let weights = Matrix::read_from(r)?;
+        let biases = Matrix::read_from(r)?;
+        let activation = Activation::read_from(r)?;
+        if biases.rows() != 1 || biases.cols() != weights.cols() {
+            return Err(invalid_data(format!(
+                "bias shape {}x{} does not fit weights {}x{}",
+                biases.rows(),
+                biases.cols(),
+                weights.rows(),
+                weights.cols()
+            )));
         }
-        result
+        Ok(Self {
+            weights,
+            biases,
+            activation,
+            weights_state: Vec::new(),
+            biases_state: Vec::new(),
+        })
     }
 
-    // Matrix multiplication
-    pub fn multiply(&self, other: &Matrix) -> Matrix {
-        if self.cols != other.rows {
-            println!("Matrix multiplication dimension mismatch:");
-            println!("Matrix A (self):");
-            self.print();
-            println!("Matrix B (other):");
-            other.print();
-            panic!(
-                "Dimensions of Matrix A (cols) and Matrix B (rows) must match for multiplication."
-            );
+    fn write_optimizer_state<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        persist::write_usize(w, self.weights_state.len())?;
+        for matrix in self.weights_state.iter().chain(&self.biases_state) {
+            matrix.write_to(w)?;
         }
-        let mut result = Matrix::new(self.rows, other.cols);
-        for i in 0..self.rows {
-            for j in 0..other.cols {
-                for k in 0..self.cols {
-                    result.data[i][j] += self.data[i][k] * other.data[k][j];
-                }
-            }
+        Ok(())
+    }
+
+    fn read_optimizer_state<R: Read>(&mut self, r: &mut R) -> io::Result<()> {
+        let slots = persist::read_usize(r)?;
+        let mut read = |shape: &Matrix<T>| -> io::Result<Vec<Matrix<T>>> {
+            (0..slots)
+                .map(|_| {
+                    let matrix = Matrix::read_from(r)?;
+                    if (matrix.rows(), matrix.cols()) != (shape.rows(), shape.cols()) {
+                        return Err(invalid_data("optimizer state does not fit its layer"));
+                    }
+                    Ok(matrix)
+                })
+                .collect()
+        };
+        self.weights_state = read(&self.weights)?;
+        self.biases_state = read(&self.biases)?;
+        Ok(())
+    }
+
+    fn cast<U: Float>(&self) -> Layer<U> {
+        let cast = |matrices: &[Matrix<T>]| matrices.iter().map(Matrix::cast).collect();
+        Layer {
+            weights: self.weights.cast(),
+            biases: self.biases.cast(),
+            activation: self.activation,
+            weights_state: cast(&self.weights_state),
+            biases_state: cast(&self.biases_state),
         }
-        result
     }
 
-    // Element-wise (Hadamard) multiplication
-    pub fn hadamard(&self, other: &Matrix) -> Matrix {
-        if self.rows != other.rows || self.cols != other.cols {
-            println!("Matrix hadamard multiplication dimension mismatch:");
-            println!("Matrix A (self):");
-            self.print();
-            println!("Matrix B (other):");
-            other.print();
-            panic!("Dimensions of Matrix A and Matrix B must match for hadamard multiplication.");
+    fn clear_optimizer_state(&mut self) {
+        self.weights_state.clear();
+        self.biases_state.clear();
+    }
+
+    // One output row per input row
+    pub fn forward(&self, input: &Matrix<T>) -> Matrix<T> {
+        let mut output = self.pre_activation(input);
+        self.activate_in_place(&mut output);
+        output
+    }
+
+    fn pre_activation(&self, input: &Matrix<T>) -> Matrix<T> {
+        input * &self.weights + &self.biases
+    }
+
+    fn pre_activation_into(&self, input: &Matrix<T>, z: &mut Matrix<T>) {
+        input.multiply_into(&self.weights, z);
+        z.add_row_in_place(&self.biases);
+    }
+
+    fn activate_in_place(&self, values: &mut Matrix<T>) {
+        let activation = self.activation;
+        if activation != Activation::Linear {
+            values.apply_in_place(|x| activation.apply(x));
         }
-        let mut result = Matrix::new(self.rows, self.cols);
-        for i in 0..self.rows {
-            for j in 0..self.cols {
-                result.data[i][j] = self.data[i][j] * other.data[i][j];
+    }
+
+    // Turns the loss gradient w.r.t. the layer's outputs into `delta`, the
+    // gradient w.r.t. their pre-activations `z`
+    fn backpropagate_activation(&self, error: &mut Matrix<T>, z: &Matrix<T>) {
+        assert_eq!((error.rows(), error.cols()), (z.rows(), z.cols()));
+        if self.activation != Activation::Linear {
+            for (e, &x) in error.as_mut_slice().iter_mut().zip(z.as_slice()) {
+                *e *= self.activation.derivative(x);
             }
         }
-        result
     }
 
-    // Scalar multiplication
-    pub fn scalar_multiply(&self, scalar: f64) -> Matrix {
-        let mut result = Matrix::new(self.rows, self.cols);
-        for i in 0..self.rows {
-            for j in 0..self.cols {
-                result.data[i][j] = self.data[i][j] * scalar;
+    fn copy_from(&mut self, source: &Layer<T>) {
+        assert_eq!(
+            (self.weights.rows(), self.weights.cols()),
+            (source.weights.rows(), source.weights.cols()),
+            "layer shapes must match"
+        );
+        self.weights.copy_from(&source.weights);
+        self.biases.copy_from(&source.biases);
+    }
+
+    fn soft_update(&mut self, source: &Layer<T>, tau: f64) {
+        let (keep, tau) = (T::from_f64(1.0 - tau), T::from_f64(tau));
+        for (target, source) in [
+            (&mut self.weights, &source.weights),
+            (&mut self.biases, &source.biases),
+        ] {
+            for (t, &s) in target.as_mut_slice().iter_mut().zip(source.as_slice()) {
+                *t = *t * keep + s * tau;
             }
         }
-        result
     }
 
-    // Transpose
-    pub fn transpose(&self) -> Matrix {
-        let mut result = Matrix::new(self.cols, self.rows);
-        for i in 0..self.rows {
-            for j in 0..self.cols {
-                result.data[j][i] = self.data[i][j];
-            }
+    // Loss gradients given the layer's input rows and `delta`, the loss
+    // gradient w.r.t. each row's pre-activation: the (weights, biases)
+    // gradients summed over the rows, and the gradient w.r.t. the input when
+    // `input_error` asks for it
+    fn gradients_into(
+        &self,
+        input: &Matrix<T>,
+        delta: &Matrix<T>,
+        gradients: &mut (Matrix<T>, Matrix<T>),
+        input_error: Option<&mut Matrix<T>>,
+    ) {
+        input.transpose_multiply_into(delta, &mut gradients.0);
+        delta.sum_rows_into(&mut gradients.1);
+        if let Some(input_error) = input_error {
+            delta.multiply_transposed_into(&self.weights, input_error);
         }
-        result
     }
 
-    // Apply a function element-wise to the matrix
-    pub fn apply<F>(&self, func: F) -> Matrix
-    where
-        F: Fn(f64) -> f64,
-    {
-        let mut result = Matrix::new(self.rows, self.cols);
-        for i in 0..self.rows {
-            for j in 0..self.cols {
-                result.data[i][j] = func(self.data[i][j]);
+    // Optimizer step, after clipping each gradient to the rule's norm
+    fn step(&mut self, gradients: &mut (Matrix<T>, Matrix<T>), rule: UpdateRule<T>) {
+        let (gradient_weights, gradient_biases) = gradients;
+        if let Some(threshold) = rule.gradient_clip {
+            let threshold = T::from_f64(threshold);
+            for gradient in [&mut *gradient_weights, &mut *gradient_biases] {
+                let norm = gradient.norm();
+                if norm > threshold {
+                    *gradient *= threshold / norm;
+                }
             }
         }
-        result
+
+        let slots = rule.optimizer.state_slots();
+        if self.weights_state.len() != slots {
+            let zeros = |m: &Matrix<T>| {
+                (0..slots)
+                    .map(|_| Matrix::new(m.rows(), m.cols()))
+                    .collect()
+            };
+            self.weights_state = zeros(&self.weights);
+            self.biases_state = zeros(&self.biases);
+        }
+        rule.optimizer.update(
+            &mut self.weights,
+            gradient_weights,
+            &mut self.weights_state,
+            rule.step,
+        );
+        rule.optimizer.update(
+            &mut self.biases,
+            gradient_biases,
+            &mut self.biases_state,
+            rule.step,
+        );
+    }
+}
+
+// Dueling head: the trunk's features feed a scalar state value and one
+// advantage per action, combined as Q = V + A - mean(A)
+#[derive(Clone)]
+struct DuelingHead<T: Float> {
+    value: Layer<T>,
+    advantage: Layer<T>,
+}
+
+impl<T: Float> DuelingHead<T> {
+    fn forward(&self, features: &Matrix<T>) -> Matrix<T> {
+        let mut q_values = self.advantage.forward(features);
+        self.combine(&self.value.pre_activation(features), &mut q_values);
+        q_values
     }
 
-    pub fn from_array_to_row(data: &[f64]) -> Self {
-        Self {
-            data: vec![data.to_vec()],
-            rows: 1,
-            cols: data.len(),
+    // Turns the advantages in `q_values` into Q-values, given the value
+    // stream's pre-activations
+    fn combine(&self, value_z: &Matrix<T>, q_values: &mut Matrix<T>) {
+        for i in 0..q_values.rows() {
+            let mean = q_values[i].iter().sum::<T>() / T::from_f64(q_values.cols() as f64);
+            let offset = self.value.activation.apply(value_z[i][0]) - mean;
+            q_values[i].iter_mut().for_each(|a| *a += offset);
         }
     }
 
-    pub fn from_array_to_column(data: &[f64]) -> Self {
-        Self {
-            data: data.iter().map(|&value| vec![value]).collect(),
-            rows: data.len(),
-            cols: 1,
+    // Backpropagates dL/dQ (one row per sample, in `g.error`) through the
+    // combination, where dQ_i/dV = 1 and dQ_i/dA_j = [i == j] - 1/n, and
+    // through both streams. Writes the (weights, biases) gradients of the
+    // value and advantage streams to `g.parameters[first]` and the one after,
+    // and leaves dL/d(features) in `g.error`.
+    fn gradients_into(
+        &self,
+        features: &Matrix<T>,
+        pass: &ForwardPass<T>,
+        g: &mut Gradients<T>,
+        first: usize,
+    ) {
+        let error = &g.error;
+        g.value_error.resize(error.rows(), 1);
+        g.advantage_error.copy_from(error);
+        for i in 0..error.rows() {
+            let error_sum: T = error[i].iter().sum();
+            g.value_error[i][0] = error_sum;
+            let mean = error_sum / T::from_f64(error.cols() as f64);
+            g.advantage_error[i].iter_mut().for_each(|e| *e -= mean);
         }
+
+        self.value
+            .backpropagate_activation(&mut g.value_error, &pass.value_z);
+        self.advantage
+            .backpropagate_activation(&mut g.advantage_error, &pass.advantage_z);
+        let (value, advantage) = g.parameters[first..].split_at_mut(1);
+        self.value
+            .gradients_into(features, &g.value_error, &mut value[0], Some(&mut g.error));
+        self.advantage.gradients_into(
+            features,
+            &g.advantage_error,
+            &mut advantage[0],
+            Some(&mut g.input_error),
+        );
+        g.error += &g.input_error;
     }
+}
 
-    pub fn random(rows: usize, cols: usize, min: f64, max: f64) -> Self {
-        let mut rng = rand::thread_rng();
-        let data = (0..rows)
-            .map(|_| {
-                (0..cols)
-                    .map(|_| rng.gen::<f64>() * (max - min) + min)
-                    .collect()
-            })
-            .collect();
-        Self { data, rows, cols }
+// What a forward pass leaves for the backward pass: every layer's input and
+// pre-activation along with the predictions, so backpropagation follows
+// exactly the function that produced them. `forward_pass_into` refills the
+// same buffers batch after batch.
+#[derive(Clone, Default)]
+pub struct ForwardPass<T: Float = f64> {
+    // Input of each trunk layer, then the trunk's output
+    inputs: Vec<Matrix<T>>,
+    pre_activations: Vec<Matrix<T>>,
+    // Pre-activations of the value and advantage streams (dueling only)
+    value_z: Matrix<T>,
+    advantage_z: Matrix<T>,
+    output: Matrix<T>,
+}
+
+impl<T: Float> ForwardPass<T> {
+    // Q-values, one row per state
+    pub fn output(&self) -> &Matrix<T> {
+        &self.output
     }
+}
 
-    pub fn norm(&self) -> f64 {
-        let mut sum = 0.0;
-        for i in 0..self.rows {
-            for j in 0..self.cols {
-                sum += self.data[i][j] * self.data[i][j];
-            }
+// Backpropagation buffers, kept by the network so a training step allocates
+// nothing once they have grown to the batch size
+#[derive(Clone, Default)]
+struct Gradients<T: Float> {
+    // (weights, biases) of every layer, in `layers()` order
+    parameters: Vec<(Matrix<T>, Matrix<T>)>,
+    // Loss gradient w.r.t. the output of the layer being processed, and
+    // w.r.t. its input
+    error: Matrix<T>,
+    input_error: Matrix<T>,
+    // Loss gradients w.r.t. the dueling streams' outputs
+    value_error: Matrix<T>,
+    advantage_error: Matrix<T>,
+}
+
+#[derive(Clone)]
+pub struct NeuralNetwork<T: Float = f64> {
+    layers: Vec<Layer<T>>, // Layers of the neural network
+    // Replaces a plain output layer when set; `layers` is then the shared trunk
+    dueling: Option<DuelingHead<T>>,
+    optimizer: OptimizerConfig,
+    gradient_clip: Option<f64>,
+    // Updates so far, for optimizers with bias correction
+    steps: u64,
+    scratch: Gradients<T>,
+}
+
+// Describes a network layer by layer, e.g.
+//
+//     NeuralNetwork::builder()
+//         .input(11)
+//         .dense(128, Activation::Relu)
+//         .dense(64, Activation::Tanh)
+//         .dense(3, Activation::Linear)
+//         .build(&mut rng)
+//
+// `dueling(outputs)` ends the stack with value and advantage streams instead
+// of a final dense layer. `NeuralNetwork::<f32>::builder()` builds an f32
+// network.
+// What keeps a `NetworkBuilder` from describing a network
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BuildError {
+    // No input size, or an input size of 0
+    NoInputs,
+    NoDenseLayers,
+    // Layer `layer`, counting from 0 with the dueling streams last, has no
+    // outputs
+    NoOutputs { layer: usize },
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BuildError::NoInputs => write!(f, "network has no inputs"),
+            BuildError::NoDenseLayers => write!(f, "network has no dense layers"),
+            BuildError::NoOutputs { layer } => write!(f, "network layer {} has no outputs", layer),
         }
-        sum.sqrt()
     }
 }
 
-use std::ops::Index;
+impl std::error::Error for BuildError {}
 
-impl Index<usize> for Matrix {
-    type Output = Vec<f64>;
+#[derive(Clone, Debug, Default)]
+pub struct NetworkBuilder<T: Float = f64> {
+    input_size: Option<usize>,
+    layers: Vec<(usize, Activation)>,
+    dueling_outputs: Option<usize>,
+    optimizer: OptimizerConfig,
+    precision: PhantomData<T>,
+}
 
-    fn index(&self, index: usize) -> &Self::Output {
-        &self.data[index]
+impl<T: Float> NetworkBuilder<T> {
+    pub fn input(mut self, size: usize) -> Self {
+        self.input_size = Some(size);
+        self
     }
-}
 
-pub struct Layer {
-    weights: Matrix,
-    biases: Matrix,
-    activation: bool,
+    pub fn dense(mut self, size: usize, activation: Activation) -> Self {
+        self.layers.push((size, activation));
+        self
+    }
+
+    pub fn dueling(mut self, outputs: usize) -> Self {
+        self.dueling_outputs = Some(outputs);
+        self
+    }
+
+    pub fn optimizer(mut self, optimizer: OptimizerConfig) -> Self {
+        self.optimizer = optimizer;
+        self
+    }
+
+    pub fn build<R: Rng>(self, rng: &mut R) -> NeuralNetwork<T> {
+        self.try_build(rng).unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    pub fn try_build<R: Rng>(self, rng: &mut R) -> Result<NeuralNetwork<T>, BuildError> {
+        let input_size = self.input_size.unwrap_or(0);
+        if input_size == 0 {
+            return Err(BuildError::NoInputs);
+        }
+        if self.layers.is_empty() {
+            return Err(BuildError::NoDenseLayers);
+        }
+        let outputs = self.layers.iter().map(|&(outputs, _)| outputs);
+        if let Some(layer) = outputs.chain(self.dueling_outputs).position(|n| n == 0) {
+            return Err(BuildError::NoOutputs { layer });
+        }
+
+        let mut size = input_size;
+        let mut layers = Vec::with_capacity(self.layers.len());
+        for (output_size, activation) in self.layers {
+            layers.push(Layer::new(size, output_size, activation, rng));
+            size = output_size;
+        }
+        let dueling = self.dueling_outputs.map(|outputs| DuelingHead {
+            value: Layer::new(size, 1, Activation::Linear, rng),
+            advantage: Layer::new(size, outputs, Activation::Linear, rng),
+        });
+        Ok(NeuralNetwork {
+            layers,
+            dueling,
+            optimizer: self.optimizer,
+            gradient_clip: Some(10.0),
+            steps: 0,
+            scratch: Gradients::default(),
+        })
+    }
 }
 
-impl Layer {
-    pub fn new(input_size: usize, output_size: usize, activation: bool) -> Self {
-        // Randomly initialize weights and biases with small values.
-        let variance = 2.0 / input_size as f64;
-        let std_dev = variance.sqrt();
+impl<T: Float> NeuralNetwork<T> {
+    pub fn builder() -> NetworkBuilder<T> {
+        NetworkBuilder::default()
+    }
 
-        let weights = Matrix::random(input_size, output_size, -std_dev, std_dev);
-        let biases = Matrix::random(1, output_size, 0.0, 0.0);
-        Self {
-            weights,
-            biases,
-            activation,
+    // One leaky ReLU hidden layer and a linear output layer
+    pub fn new<R: Rng>(
+        input_size: usize,
+        hidden_size: usize,
+        output_size: usize,
+        rng: &mut R,
+    ) -> Self {
+        Self::builder()
+            .input(input_size)
+            .dense(hidden_size, Activation::LeakyRelu(0.01))
+            .dense(output_size, Activation::Linear)
+            .build(rng)
+    }
+
+    // Same hidden layer as `new`, followed by separate value and advantage
+    // streams instead of a single output layer
+    pub fn dueling<R: Rng>(
+        input_size: usize,
+        hidden_size: usize,
+        output_size: usize,
+        rng: &mut R,
+    ) -> Self {
+        Self::builder()
+            .input(input_size)
+            .dense(hidden_size, Activation::LeakyRelu(0.01))
+            .dueling(output_size)
+            .build(rng)
+    }
+
+    pub fn optimizer(&self) -> OptimizerConfig {
+        self.optimizer
+    }
+
+    // Switches the update rule. Its state starts afresh unless only the
+    // hyperparameters of the same kind of optimizer change.
+    pub fn set_optimizer(&mut self, optimizer: OptimizerConfig) {
+        if std::mem::discriminant(&optimizer) != std::mem::discriminant(&self.optimizer) {
+            self.steps = 0;
+            self.layers_mut()
+                .for_each(|layer| layer.clear_optimizer_state());
+        }
+        self.optimizer = optimizer;
+    }
+
+    pub fn gradient_clip(&self) -> Option<f64> {
+        self.gradient_clip
+    }
+
+    // `None` turns clipping off; the default clips at a norm of 10
+    pub fn set_gradient_clip(&mut self, gradient_clip: Option<f64>) {
+        self.gradient_clip = gradient_clip;
+    }
+
+    // Trunk layers first, then the dueling streams
+    fn layers(&self) -> impl Iterator<Item = &Layer<T>> {
+        let head = self
+            .dueling
+            .iter()
+            .flat_map(|head| [&head.value, &head.advantage]);
+        self.layers.iter().chain(head)
+    }
+
+    fn layers_mut(&mut self) -> impl Iterator<Item = &mut Layer<T>> {
+        let head = self
+            .dueling
+            .iter_mut()
+            .flat_map(|head| [&mut head.value, &mut head.advantage]);
+        self.layers.iter_mut().chain(head)
+    }
+
+    pub fn is_dueling(&self) -> bool {
+        self.dueling.is_some()
+    }
+
+    pub fn input_size(&self) -> usize {
+        self.layers[0].weights.rows()
+    }
+
+    pub fn output_size(&self) -> usize {
+        match &self.dueling {
+            Some(head) => head.advantage.weights.cols(),
+            None => self.layers[self.layers.len() - 1].weights.cols(),
+        }
+    }
+
+    // Same kind of head and the same layers, of the same shapes and activations
+    pub fn same_architecture(&self, other: &NeuralNetwork<T>) -> bool {
+        let layout = |layer: &Layer<T>| (layer.weights.shape(), layer.activation);
+        self.is_dueling() == other.is_dueling()
+            && self.layers().map(layout).eq(other.layers().map(layout))
+    }
+
+    fn check_same_architecture(&self, other: &NeuralNetwork<T>) {
+        assert_eq!(
+            self.layers.len(),
+            other.layers.len(),
+            "networks must have the same number of layers"
+        );
+        assert_eq!(
+            self.is_dueling(),
+            other.is_dueling(),
+            "networks must have the same kind of head"
+        );
+    }
+
+    // Overwrite every weight and bias with those of `other`, which must have
+    // the same architecture
+    pub fn copy_from(&mut self, other: &NeuralNetwork<T>) {
+        self.check_same_architecture(other);
+        for (layer, source) in self.layers.iter_mut().zip(&other.layers) {
+            layer.copy_from(source);
+        }
+        if let (Some(head), Some(source)) = (&mut self.dueling, &other.dueling) {
+            head.value.copy_from(&source.value);
+            head.advantage.copy_from(&source.advantage);
+        }
+    }
+
+    // Polyak averaging: move every parameter a fraction `tau` towards `other`
+    pub fn soft_update(&mut self, other: &NeuralNetwork<T>, tau: f64) {
+        self.check_same_architecture(other);
+        for (layer, source) in self.layers.iter_mut().zip(&other.layers) {
+            layer.soft_update(source, tau);
+        }
+        if let (Some(head), Some(source)) = (&mut self.dueling, &other.dueling) {
+            head.value.soft_update(&source.value, tau);
+            head.advantage.soft_update(&source.advantage, tau);
+        }
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut w = BufWriter::new(File::create(path)?);
+        self.write_to(&mut w)?;
+        w.flush()
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<NeuralNetwork<T>> {
+        NeuralNetwork::read_from(&mut BufReader::new(File::open(path)?))
+    }
+
+    pub fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        persist::write_magic(w, MODEL_MAGIC, MODEL_VERSION)?;
+        T::PRECISION.write_to(w)?;
+        persist::write_usize(w, self.layers.len())?;
+        for layer in &self.layers {
+            layer.write_to(w)?;
+        }
+        persist::write_bool(w, self.is_dueling())?;
+        if let Some(head) = &self.dueling {
+            head.value.write_to(w)?;
+            head.advantage.write_to(w)?;
+        }
+        self.optimizer.write_to(w)?;
+        persist::write_bool(w, self.gradient_clip.is_some())?;
+        persist::write_f64(w, self.gradient_clip.unwrap_or(0.0))?;
+        persist::write_u64(w, self.steps)?;
+        for layer in self.layers() {
+            layer.write_optimizer_state(w)?;
+        }
+        Ok(())
+    }
+
+    // Loads a model saved in either precision, converting it to `T`
+    pub fn read_from<R: Read>(r: &mut R) -> io::Result<NeuralNetwork<T>> {
+        match read_precision(r)? {
+            Precision::F32 => Ok(NeuralNetwork::<f32>::read_body(r)?.cast()),
+            Precision::F64 => Ok(NeuralNetwork::<f64>::read_body(r)?.cast()),
         }
     }
 
-    pub fn forward(&self, input: &Matrix) -> Matrix {
-        let z = input.multiply(&self.weights).add(&self.biases);
-        if self.activation {
-            z.apply(|x| if x > 0.0 { x } else { 0.01 * x })
+    // The rest of a model stored in `T`
+    fn read_body<R: Read>(r: &mut R) -> io::Result<NeuralNetwork<T>> {
+        let layer_count = persist::read_usize(r)?;
+        if layer_count == 0 {
+            return Err(invalid_data("model has no layers"));
+        }
+        let layers = (0..layer_count)
+            .map(|_| Layer::read_from(r))
+            .collect::<io::Result<Vec<_>>>()?;
+        let dueling = if persist::read_bool(r)? {
+            Some(DuelingHead {
+                value: Layer::read_from(r)?,
+                advantage: Layer::read_from(r)?,
+            })
         } else {
-            z
+            None
+        };
+
+        let mut chain: Vec<&Layer<T>> = layers.iter().collect();
+        if let Some(head) = &dueling {
+            if head.value.weights.cols() != 1 {
+                return Err(invalid_data("value stream must have a single output"));
+            }
+            if head.value.weights.rows() != head.advantage.weights.rows() {
+                return Err(invalid_data(
+                    "value and advantage streams take different inputs",
+                ));
+            }
+            chain.push(&head.advantage);
+        }
+        for pair in chain.windows(2) {
+            if pair[0].weights.cols() != pair[1].weights.rows() {
+                return Err(invalid_data(format!(
+                    "layer with {} outputs feeds a layer with {} inputs",
+                    pair[0].weights.cols(),
+                    pair[1].weights.rows()
+                )));
+            }
         }
+
+        let optimizer = OptimizerConfig::read_from(r)?;
+        let clip = persist::read_bool(r)?;
+        let norm = persist::read_f64(r)?;
+        let mut network = Self {
+            layers,
+            dueling,
+            optimizer,
+            gradient_clip: clip.then_some(norm),
+            steps: persist::read_u64(r)?,
+            scratch: Gradients::default(),
+        };
+        for layer in network.layers_mut() {
+            layer.read_optimizer_state(r)?;
+        }
+        Ok(network)
     }
-}
 
-pub struct NeuralNetwork {
-    layers: Vec<Layer>, // Layers of the neural network
-    learning_rate: f64, // Learning rate for optimization
-}
+    // The same network computing in `U`, optimizer state included, e.g. an
+    // f32 network widened to f64 for a gradient check
+    pub fn cast<U: Float>(&self) -> NeuralNetwork<U> {
+        NeuralNetwork {
+            layers: self.layers.iter().map(Layer::cast).collect(),
+            dueling: self.dueling.as_ref().map(|head| DuelingHead {
+                value: head.value.cast(),
+                advantage: head.advantage.cast(),
+            }),
+            optimizer: self.optimizer,
+            gradient_clip: self.gradient_clip,
+            steps: self.steps,
+            scratch: Gradients::default(),
+        }
+    }
 
-impl NeuralNetwork {
-    pub fn new(input_size: usize, hidden_size: usize, output_size: usize) -> Self {
-        // Initialize one hidden layer and one output layer.
-        let input_layer = Layer::new(input_size, hidden_size, true);
-        let hidden_layer = Layer::new(hidden_size, output_size, false);
-        Self {
-            layers: vec![input_layer, hidden_layer],
-            learning_rate: 0.001, // Some default value; can be adjusted
+    // States that don't fit the first layer, e.g. from another board than
+    // the model was saved for, are reported against its weights rather than
+    // deep inside the first product
+    fn check_input(&self, states: (usize, usize)) -> Result<(), ShapeError> {
+        if states.1 == self.input_size() {
+            return Ok(());
         }
+        Err(ShapeError {
+            operation: "network input",
+            left: states,
+            right: self.layers[0].weights.shape(),
+        })
+    }
+
+    pub fn forward(&self, state: &[T]) -> Vec<T> {
+        or_panic(self.try_forward(state))
     }
 
-    pub fn forward(&self, state: &[i32; 12]) -> [f64; 4] {
-        let state_f64: Vec<f64> = state.iter().map(|&x| x as f64).collect();
-        let mut input = Matrix::from_array_to_row(&state_f64);
+    pub fn try_forward(&self, state: &[T]) -> Result<Vec<T>, ShapeError> {
+        self.check_input((1, state.len()))?;
+        let mut input = Matrix::from_array_to_row(state);
 
         for layer in &self.layers {
             input = layer.forward(&input);
         }
-        assert_eq!(input.rows, 1);
-        assert_eq!(input.cols, 4);
-        // Convert the final matrix to an array of size 4
-        [
-            input[0][0] as f64,
-            input[0][1] as f64,
-            input[0][2] as f64,
-            input[0][3] as f64,
-        ]
+        if let Some(head) = &self.dueling {
+            input = head.forward(&input);
+        }
+        assert_eq!(input.rows(), 1);
+        Ok(input[0].to_vec())
+    }
+
+    // Q-values for every row of `states` (one state per row)
+    pub fn forward_batch(&self, states: &Matrix<T>) -> Matrix<T> {
+        or_panic(self.try_forward_batch(states))
+    }
+
+    pub fn try_forward_batch(&self, states: &Matrix<T>) -> Result<Matrix<T>, ShapeError> {
+        self.check_input(states.shape())?;
+        let mut input = self.layers[0].forward(states);
+        for layer in &self.layers[1..] {
+            input = layer.forward(&input);
+        }
+        Ok(match &self.dueling {
+            Some(head) => head.forward(&input),
+            None => input,
+        })
+    }
+
+    // `forward_batch` that keeps what `backward_pass` needs
+    pub fn forward_pass(&self, states: &Matrix<T>) -> ForwardPass<T> {
+        let mut pass = ForwardPass::default();
+        self.forward_pass_into(states, &mut pass);
+        pass
+    }
+
+    // `forward_pass` into the buffers of an earlier pass, which allocates
+    // nothing once they are big enough
+    pub fn forward_pass_into(&self, states: &Matrix<T>, pass: &mut ForwardPass<T>) {
+        or_panic(self.check_input(states.shape()));
+        let depth = self.layers.len();
+        pass.inputs.resize_with(depth + 1, Matrix::default);
+        pass.pre_activations.resize_with(depth, Matrix::default);
+        pass.inputs[0].copy_from(states);
+        for (idx, layer) in self.layers.iter().enumerate() {
+            layer.pre_activation_into(&pass.inputs[idx], &mut pass.pre_activations[idx]);
+            pass.inputs[idx + 1].copy_from(&pass.pre_activations[idx]);
+            layer.activate_in_place(&mut pass.inputs[idx + 1]);
+        }
+        let features = &pass.inputs[depth];
+        match &self.dueling {
+            Some(head) => {
+                head.value.pre_activation_into(features, &mut pass.value_z);
+                head.advantage
+                    .pre_activation_into(features, &mut pass.advantage_z);
+                pass.output.copy_from(&pass.advantage_z);
+                head.advantage.activate_in_place(&mut pass.output);
+                head.combine(&pass.value_z, &mut pass.output);
+            }
+            None => pass.output.copy_from(features),
+        }
+    }
+
+    // Gradients of the weighted mean squared error
+    //   sum_i weights[i] * |output_i - target_i|^2 / rows
+    // for every parameter, as (weights, biases) in trunk-then-head order
+    fn gradients_into(
+        &self,
+        pass: &ForwardPass<T>,
+        target_qvalues: &Matrix<T>,
+        weights: &[f64],
+        g: &mut Gradients<T>,
+    ) {
+        assert_eq!(pass.output.rows(), weights.len(), "one weight per sample");
+        g.parameters
+            .resize_with(self.layers().count(), Default::default);
+
+        // Gradient of the loss with respect to each prediction
+        g.error.copy_from(&pass.output);
+        g.error -= target_qvalues;
+        let batch_size = pass.output.rows() as f64;
+        for (i, weight) in weights.iter().enumerate() {
+            let scale = T::from_f64(2.0 * weight / batch_size);
+            g.error[i].iter_mut().for_each(|e| *e *= scale);
+        }
+
+        let depth = self.layers.len();
+        if let Some(head) = &self.dueling {
+            head.gradients_into(&pass.inputs[depth], pass, g, depth);
+        }
+        for (idx, layer) in self.layers.iter().enumerate().rev() {
+            layer.backpropagate_activation(&mut g.error, &pass.pre_activations[idx]);
+            // Nothing needs the gradient w.r.t. the states
+            let input_error = (idx > 0).then_some(&mut g.input_error);
+            layer.gradients_into(
+                &pass.inputs[idx],
+                &g.error,
+                &mut g.parameters[idx],
+                input_error,
+            );
+            std::mem::swap(&mut g.error, &mut g.input_error);
+        }
+    }
+
+    // One optimizer step on the weighted mean squared error between the
+    // predictions of `pass`, which must come from this network's current
+    // weights, and `target_qvalues`. `weights` scales each sample's loss
+    // (all 1.0 for a plain mean).
+    pub fn backward_pass(
+        &mut self,
+        pass: &ForwardPass<T>,
+        target_qvalues: &Matrix<T>,
+        weights: &[f64],
+    ) {
+        let mut gradients = std::mem::take(&mut self.scratch);
+        self.gradients_into(pass, target_qvalues, weights, &mut gradients);
+        self.steps += 1;
+        let optimizer = self.optimizer;
+        let rule = UpdateRule {
+            optimizer: optimizer.optimizer(),
+            step: self.steps,
+            gradient_clip: self.gradient_clip,
+        };
+        for (layer, gradients) in self.layers_mut().zip(&mut gradients.parameters) {
+            layer.step(gradients, rule);
+        }
+        self.scratch = gradients;
     }
 
-    pub fn backward(
+    // Minibatch gradient descent: one step on the gradient averaged over the
+    // rows of `states`
+    pub fn backward_batch(
         &mut self,
-        state: &[i32; 12],
-        target_qvalues: [f64; 4],
-        predicted_qvalues: [f64; 4],
+        states: &Matrix<T>,
+        target_qvalues: &Matrix<T>,
+        weights: &[f64],
     ) {
-  
-        // Gradient of MSE loss with respect to predicted Q-values
-        let mut error = Matrix::from_array_to_column(&[
-            2.0 * (predicted_qvalues[0] - target_qvalues[0]) as f64,
-            2.0 * (predicted_qvalues[1] - target_qvalues[1]) as f64,
-            2.0 * (predicted_qvalues[2] - target_qvalues[2]) as f64,
-            2.0 * (predicted_qvalues[3] - target_qvalues[3]) as f64,
-        ]);
+        let pass = self.forward_pass(states);
+        self.backward_pass(&pass, target_qvalues, weights);
+    }
 
-        let mut derivatives: Vec<Matrix> = Vec::new();
-        let mut activations: Vec<Matrix> = Vec::new();
-        let state_f64: Vec<f64> = state.iter().map(|&x| x as f64).collect();
+    // One step towards `target_qvalues` for a single state
+    pub fn backward(&mut self, state: &[T], target_qvalues: &[T]) {
+        let pass = self.forward_pass(&Matrix::from_array_to_row(state));
+        self.backward_pass(&pass, &Matrix::from_array_to_row(target_qvalues), &[1.0]);
+    }
+}
 
-        activations.push(Matrix::from_array_to_row(&state_f64));
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::optimizer::Sgd;
+    use crate::rng;
 
-        // Derivative computation and storage (using ReLU derivative)
+    const STEP: f64 = 1e-6;
 
-        let mut input = activations[0].clone();
-        for layer in &self.layers {
-            let z = input.multiply(&layer.weights).add(&layer.biases);
-            let a = z.apply(|x| if x > 0.0 { x } else { 0.01 * x });
-            activations.push(a.clone());
-            let da = z.apply(|x| if x > 0.0 { 1.0 } else { 0.01 });
-            derivatives.push(da.transpose());
-            input = a;
-        }
-
-        // Step 2: Backward pass through the layers
-        for (idx, layer) in self.layers.iter_mut().enumerate().rev() {
-            let derror_dweights = error.hadamard(&derivatives[idx]);
-            let mut gradient_weights = activations[idx]
-                .transpose()
-                .multiply(&derror_dweights.transpose());
-            let mut gradient_biases = derror_dweights.transpose();
-            // Step 3: Weight Update using Gradient Descent
-
-            // Gradient clipping
-            let threshold: f64 = 10.0;
-            if gradient_weights.norm() > threshold {
-                gradient_weights =
-                    gradient_weights.scalar_multiply(threshold / gradient_weights.norm());
+    fn loss(network: &NeuralNetwork, states: &Matrix, targets: &Matrix, weights: &[f64]) -> f64 {
+        let error = network.forward_batch(states) - targets;
+        let total: f64 = (0..error.rows())
+            .map(|i| weights[i] * error.row(i).iter().map(|e| e * e).sum::<f64>())
+            .sum();
+        total / states.rows() as f64
+    }
+
+    fn gradients(
+        network: &NeuralNetwork,
+        states: &Matrix,
+        targets: &Matrix,
+        weights: &[f64],
+    ) -> Vec<(Matrix, Matrix)> {
+        let mut gradients = Gradients::default();
+        network.gradients_into(
+            &network.forward_pass(states),
+            targets,
+            weights,
+            &mut gradients,
+        );
+        gradients.parameters
+    }
+
+    // Weights (`which == 0`) or biases of the `layer`-th layer, trunk first
+    fn parameter(network: &mut NeuralNetwork, layer: usize, which: usize) -> &mut Matrix {
+        let layer = network.layers_mut().nth(layer).unwrap();
+        if which == 0 {
+            &mut layer.weights
+        } else {
+            &mut layer.biases
+        }
+    }
+
+    // Compares every backpropagated gradient with a central difference of
+    // the loss
+    fn check_gradients(network: &NeuralNetwork) {
+        let mut rng = rng::stream(7, 0);
+        let states = Matrix::random(5, network.input_size(), -1.0, 1.0, &mut rng);
+        let targets = Matrix::random(5, network.output_size(), -1.0, 1.0, &mut rng);
+        let weights = [1.0, 0.5, 2.0, 0.25, 1.0];
+
+        let gradients = gradients(network, &states, &targets, &weights);
+        assert_eq!(gradients.len(), network.layers().count());
+        for (layer, (gradient_weights, gradient_biases)) in gradients.iter().enumerate() {
+            for (which, analytic) in [gradient_weights, gradient_biases].into_iter().enumerate() {
+                for i in 0..analytic.rows() {
+                    for j in 0..analytic.cols() {
+                        let mut plus = network.clone();
+                        parameter(&mut plus, layer, which)[(i, j)] += STEP;
+                        let mut minus = network.clone();
+                        parameter(&mut minus, layer, which)[(i, j)] -= STEP;
+                        let numeric = (loss(&plus, &states, &targets, &weights)
+                            - loss(&minus, &states, &targets, &weights))
+                            / (2.0 * STEP);
+
+                        let expected = analytic[(i, j)];
+                        let tolerance = 1e-6 * (1.0 + numeric.abs().max(expected.abs()));
+                        assert!(
+                            (numeric - expected).abs() < tolerance,
+                            "layer {} {} [{}][{}]: backprop {} vs finite difference {}",
+                            layer,
+                            if which == 0 { "weights" } else { "biases" },
+                            i,
+                            j,
+                            expected,
+                            numeric
+                        );
+                    }
+                }
             }
-            if gradient_biases.norm() > threshold {
-                gradient_biases =
-                    gradient_biases.scalar_multiply(threshold / gradient_biases.norm());
+        }
+    }
+
+    const ACTIVATIONS: [Activation; 6] = [
+        Activation::Relu,
+        Activation::LeakyRelu(0.1),
+        Activation::Tanh,
+        Activation::Sigmoid,
+        Activation::Elu(1.0),
+        Activation::Linear,
+    ];
+
+    #[test]
+    fn activation_derivatives_match_finite_differences() {
+        for activation in ACTIVATIONS {
+            for x in [-2.0, -0.7, -0.1, 0.2, 0.9, 3.0] {
+                let numeric =
+                    (activation.apply(x + STEP) - activation.apply(x - STEP)) / (2.0 * STEP);
+                assert!(
+                    (numeric - activation.derivative(x)).abs() < 1e-6,
+                    "{:?} at {}",
+                    activation,
+                    x
+                );
             }
+        }
+    }
 
-            layer.weights = layer
-                .weights
-                .subtract(&gradient_weights.scalar_multiply(self.learning_rate));
+    #[test]
+    fn gradients_match_for_every_activation() {
+        for activation in ACTIVATIONS {
+            let mut rng = rng::stream(1, 0);
+            let network = NeuralNetwork::builder()
+                .input(3)
+                .dense(4, activation)
+                .dense(2, activation)
+                .build(&mut rng);
+            check_gradients(&network);
+        }
+    }
+
+    #[test]
+    fn gradients_match_for_a_deep_stack() {
+        let mut rng = rng::stream(2, 0);
+        let network = NeuralNetwork::builder()
+            .input(4)
+            .dense(6, Activation::Relu)
+            .dense(5, Activation::Tanh)
+            .dense(5, Activation::Elu(0.5))
+            .dense(4, Activation::Sigmoid)
+            .dense(3, Activation::Linear)
+            .build(&mut rng);
+        check_gradients(&network);
+    }
+
+    #[test]
+    fn gradients_match_for_the_default_networks() {
+        let mut rng = rng::stream(3, 0);
+        check_gradients(&NeuralNetwork::new(5, 6, 3, &mut rng));
+        check_gradients(&NeuralNetwork::dueling(5, 6, 3, &mut rng));
+    }
+
+    #[test]
+    fn gradients_match_for_a_dueling_head_on_a_deep_trunk() {
+        let mut rng = rng::stream(4, 0);
+        let network = NeuralNetwork::builder()
+            .input(3)
+            .dense(5, Activation::LeakyRelu(0.2))
+            .dense(4, Activation::Tanh)
+            .dueling(3)
+            .build(&mut rng);
+        check_gradients(&network);
+    }
 
-            layer.biases = layer
-                .biases
-                .subtract(&gradient_biases.scalar_multiply(self.learning_rate));
+    // An f32 copy of a network computes the same Q-values and gradients up
+    // to f32 rounding
+    #[test]
+    fn f32_networks_follow_their_f64_originals() {
+        let mut rng = rng::stream(6, 0);
+        let network: NeuralNetwork = NeuralNetwork::dueling(5, 6, 3, &mut rng);
+        let single = network.cast::<f32>();
+        let states = Matrix::random(4, 5, -1.0, 1.0, &mut rng);
+        let targets = Matrix::random(4, 3, -1.0, 1.0, &mut rng);
+        let weights = [1.0, 0.5, 2.0, 0.25];
 
-            // Update error for the next iteration (if there's another layer)
-            error = layer.weights.multiply(&derror_dweights);
+        let close = |a: &Matrix<f32>, e: &Matrix| {
+            let pairs = a.as_slice().iter().zip(e.as_slice());
+            pairs.for_each(|(&a, &e)| assert!((a as f64 - e).abs() < 1e-5, "{} vs {}", a, e));
+        };
+        close(
+            &single.forward_batch(&states.cast()),
+            &network.forward_batch(&states),
+        );
+        let mut expected = Gradients::default();
+        network.gradients_into(
+            &network.forward_pass(&states),
+            &targets,
+            &weights,
+            &mut expected,
+        );
+        let mut actual = Gradients::default();
+        let pass = single.forward_pass(&states.cast());
+        single.gradients_into(&pass, &targets.cast(), &weights, &mut actual);
+        for (a, e) in actual.parameters.iter().zip(&expected.parameters) {
+            close(&a.0, &e.0);
+            close(&a.1, &e.1);
         }
     }
+
+    fn saved<T: Float>(network: &NeuralNetwork<T>) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        network.write_to(&mut bytes).unwrap();
+        bytes
+    }
+
+    // Optimizer state included, an f32 model reloads unchanged in f32 and
+    // exactly widened in f64
+    #[test]
+    fn models_load_in_either_precision() {
+        let mut rng = rng::stream(7, 0);
+        let mut network = NeuralNetwork::<f32>::dueling(5, 6, 3, &mut rng);
+        network.set_optimizer("adam".parse().unwrap());
+        network.backward(&[0.1, 0.2, 0.3, 0.4, 0.5], &[1.0, 0.0, -1.0]);
+        let bytes = saved(&network);
+        let precision = read_precision(&mut bytes.as_slice()).unwrap();
+        assert_eq!(precision, Precision::F32);
+
+        let single = NeuralNetwork::<f32>::read_from(&mut bytes.as_slice()).unwrap();
+        assert_eq!(saved(&single), bytes);
+        let double = NeuralNetwork::<f64>::read_from(&mut bytes.as_slice()).unwrap();
+        assert_eq!(saved(&double), saved(&network.cast::<f64>()));
+        assert!(bytes.len() < saved(&double).len());
+    }
+
+    #[test]
+    fn soft_updates_move_every_parameter_by_tau() {
+        let mut rng = rng::stream(3, 0);
+        let mut target = NeuralNetwork::dueling(4, 5, 3, &mut rng);
+        let mut online = target.clone();
+        for layer in target.layers_mut() {
+            layer.weights.as_mut_slice().fill(1.0);
+            layer.biases.as_mut_slice().fill(-2.0);
+        }
+        for layer in online.layers_mut() {
+            layer.weights.as_mut_slice().fill(3.0);
+            layer.biases.as_mut_slice().fill(2.0);
+        }
+
+        target.soft_update(&online, 0.25);
+        assert_eq!(target.layers().count(), 3);
+        for layer in target.layers() {
+            assert!(layer.weights.as_slice().iter().all(|&w| w == 1.5));
+            assert!(layer.biases.as_slice().iter().all(|&b| b == -1.0));
+        }
+        target.copy_from(&online);
+        for layer in target.layers() {
+            assert!(layer.weights.as_slice().iter().all(|&w| w == 3.0));
+            assert!(layer.biases.as_slice().iter().all(|&b| b == 2.0));
+        }
+    }
+
+    fn state_slots(network: &NeuralNetwork) -> Vec<usize> {
+        network
+            .layers()
+            .flat_map(|layer| [layer.weights_state.len(), layer.biases_state.len()])
+            .collect()
+    }
+
+    #[test]
+    fn optimizer_state_appears_on_the_first_update_and_resets_on_a_switch() {
+        let mut rng = rng::stream(4, 0);
+        let mut network: NeuralNetwork = NeuralNetwork::dueling(3, 4, 2, &mut rng);
+        network.set_optimizer("adam".parse().unwrap());
+        assert_eq!(state_slots(&network), [0; 6]);
+
+        network.backward(&[0.1, 0.2, 0.3], &[1.0, -1.0]);
+        assert_eq!((state_slots(&network), network.steps), (vec![2; 6], 1));
+
+        // Another learning rate for the same optimizer carries on
+        let faster = network.optimizer().with_learning_rate(0.01);
+        network.set_optimizer(faster);
+        assert_eq!((state_slots(&network), network.steps), (vec![2; 6], 1));
+
+        // Another kind of optimizer starts from nothing
+        network.set_optimizer("momentum".parse().unwrap());
+        assert_eq!((state_slots(&network), network.steps), (vec![0; 6], 0));
+        network.backward(&[0.1, 0.2, 0.3], &[1.0, -1.0]);
+        assert_eq!((state_slots(&network), network.steps), (vec![1; 6], 1));
+    }
+
+    #[test]
+    fn reloaded_optimizer_state_continues_exactly() {
+        let mut rng = rng::stream(6, 0);
+        for optimizer in ["momentum", "rmsprop", "adam"] {
+            let mut network: NeuralNetwork = NeuralNetwork::dueling(3, 4, 2, &mut rng);
+            network.set_optimizer(optimizer.parse().unwrap());
+            network.backward(&[0.1, 0.2, 0.3], &[1.0, -1.0]);
+            network.backward(&[0.3, -0.2, 0.1], &[0.0, 2.0]);
+            let mut reloaded: NeuralNetwork =
+                NeuralNetwork::read_from(&mut saved(&network).as_slice()).unwrap();
+
+            for network in [&mut network, &mut reloaded] {
+                network.backward(&[-0.5, 0.5, 1.0], &[0.5, 0.5]);
+            }
+            assert_eq!(saved(&reloaded), saved(&network), "{}", optimizer);
+        }
+    }
+
+    // With plain gradient descent and no clipping, a backward step moves
+    // every parameter by exactly -learning_rate * gradient
+    #[test]
+    fn backward_steps_along_the_checked_gradient() {
+        let mut rng = rng::stream(5, 0);
+        let mut network = NeuralNetwork::builder()
+            .input(3)
+            .dense(4, Activation::Tanh)
+            .dense(2, Activation::Linear)
+            .optimizer(OptimizerConfig::Sgd(Sgd { learning_rate: 0.1 }))
+            .build(&mut rng);
+        network.set_gradient_clip(None);
+        let state = [0.3, -0.5, 0.8];
+        let target = [1.0, -1.0];
+
+        let before = network.clone();
+        let states = Matrix::from_array_to_row(&state);
+        let targets = Matrix::from_array_to_row(&target);
+        let gradients = gradients(&before, &states, &targets, &[1.0]);
+        network.backward(&state, &target);
+
+        let updated = network.layers().zip(before.layers()).zip(&gradients);
+        for ((layer, old), (gradient_weights, gradient_biases)) in updated {
+            for (new, old, gradient) in [
+                (&layer.weights, &old.weights, gradient_weights),
+                (&layer.biases, &old.biases, gradient_biases),
+            ] {
+                for i in 0..new.rows() {
+                    for j in 0..new.cols() {
+                        let expected = old[(i, j)] - 0.1 * gradient[(i, j)];
+                        assert!((new[(i, j)] - expected).abs() < 1e-12);
+                    }
+                }
+            }
+        }
+        assert!(
+            loss(&network, &states, &targets, &[1.0]) < loss(&before, &states, &targets, &[1.0])
+        );
+    }
+
+    #[test]
+    fn bad_shapes_are_errors_from_the_try_versions() {
+        let mut rng = rng::stream(1, 0);
+        let error = |builder: NetworkBuilder| builder.try_build(&mut rng::stream(1, 0)).err();
+        let builder = || NeuralNetwork::builder().dense(8, Activation::Relu);
+        assert_eq!(error(builder()), Some(BuildError::NoInputs));
+        assert_eq!(error(builder().input(0)), Some(BuildError::NoInputs));
+        assert_eq!(
+            error(builder().input(4).dense(0, Activation::Linear)),
+            Some(BuildError::NoOutputs { layer: 1 })
+        );
+        assert_eq!(
+            error(builder().input(4).dueling(0)).unwrap().to_string(),
+            "network layer 1 has no outputs"
+        );
+        assert_eq!(
+            error(NeuralNetwork::builder().input(4).dueling(2)),
+            Some(BuildError::NoDenseLayers)
+        );
+
+        let network: NeuralNetwork = builder().input(4).dueling(2).try_build(&mut rng).unwrap();
+        assert_eq!(network.try_forward(&[0.5; 4]).unwrap().len(), 2);
+        assert_eq!(
+            network.try_forward(&[0.5; 3]).unwrap_err().to_string(),
+            "shape mismatch in network input: 1x3 and 4x8"
+        );
+        assert!(network.try_forward_batch(&Matrix::new(5, 4)).is_ok());
+        assert!(network.try_forward_batch(&Matrix::new(5, 6)).is_err());
+    }
 }
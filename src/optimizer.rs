@@ -0,0 +1,438 @@
+// Update rules for gradient descent. The rules themselves only hold their
+// hyperparameters; the per-parameter state they need (momentum, running
+// averages of the gradient) lives in each `Layer` next to its weights.
+// Hyperparameters are f64 and converted to the parameters' `Float` for each
+// update.
+use crate::float::Float;
+use crate::nn::Matrix;
+use crate::persist::{self, invalid_data};
+use std::io::{self, Read, Write};
+use std::str::FromStr;
+
+pub trait Optimizer<T: Float = f64> {
+    fn learning_rate(&self) -> f64;
+
+    // Matrices of state kept for every parameter matrix
+    fn state_slots(&self) -> usize;
+
+    // Moves `parameters` against `gradient`. `state` holds `state_slots()`
+    // matrices shaped like `parameters` (zero before the first update) and
+    // `step` counts the updates so far, starting at 1.
+    fn update(
+        &self,
+        parameters: &mut Matrix<T>,
+        gradient: &Matrix<T>,
+        state: &mut [Matrix<T>],
+        step: u64,
+    );
+}
+
+fn check_shapes<T: Float>(parameters: &Matrix<T>, gradient: &Matrix<T>, state: &[Matrix<T>]) {
+    let shape = (parameters.rows(), parameters.cols());
+    assert_eq!(
+        (gradient.rows(), gradient.cols()),
+        shape,
+        "gradient shape must match the parameters"
+    );
+    for matrix in state {
+        assert_eq!(
+            (matrix.rows(), matrix.cols()),
+            shape,
+            "optimizer state shape must match the parameters"
+        );
+    }
+}
+
+// Plain gradient descent
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Sgd {
+    pub learning_rate: f64,
+}
+
+impl<T: Float> Optimizer<T> for Sgd {
+    fn learning_rate(&self) -> f64 {
+        self.learning_rate
+    }
+
+    fn state_slots(&self) -> usize {
+        0
+    }
+
+    fn update(
+        &self,
+        parameters: &mut Matrix<T>,
+        gradient: &Matrix<T>,
+        state: &mut [Matrix<T>],
+        _: u64,
+    ) {
+        check_shapes(parameters, gradient, state);
+        let learning_rate = T::from_f64(self.learning_rate);
+        for (p, &g) in parameters
+            .as_mut_slice()
+            .iter_mut()
+            .zip(gradient.as_slice())
+        {
+            *p -= learning_rate * g;
+        }
+    }
+}
+
+// Gradient descent along an exponentially decaying sum of past gradients
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Momentum {
+    pub learning_rate: f64,
+    pub momentum: f64,
+}
+
+impl<T: Float> Optimizer<T> for Momentum {
+    fn learning_rate(&self) -> f64 {
+        self.learning_rate
+    }
+
+    fn state_slots(&self) -> usize {
+        1
+    }
+
+    fn update(
+        &self,
+        parameters: &mut Matrix<T>,
+        gradient: &Matrix<T>,
+        state: &mut [Matrix<T>],
+        _: u64,
+    ) {
+        check_shapes(parameters, gradient, state);
+        let learning_rate = T::from_f64(self.learning_rate);
+        let momentum = T::from_f64(self.momentum);
+        let velocity = state[0].as_mut_slice();
+        let elements = parameters
+            .as_mut_slice()
+            .iter_mut()
+            .zip(gradient.as_slice());
+        for ((p, &g), v) in elements.zip(velocity) {
+            *v = momentum * *v + g;
+            *p -= learning_rate * *v;
+        }
+    }
+}
+
+// Scales each step by a running root mean square of the gradient
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RmsProp {
+    pub learning_rate: f64,
+    pub decay: f64,
+    pub epsilon: f64,
+}
+
+impl<T: Float> Optimizer<T> for RmsProp {
+    fn learning_rate(&self) -> f64 {
+        self.learning_rate
+    }
+
+    fn state_slots(&self) -> usize {
+        1
+    }
+
+    fn update(
+        &self,
+        parameters: &mut Matrix<T>,
+        gradient: &Matrix<T>,
+        state: &mut [Matrix<T>],
+        _: u64,
+    ) {
+        check_shapes(parameters, gradient, state);
+        let learning_rate = T::from_f64(self.learning_rate);
+        let (decay, rest) = (T::from_f64(self.decay), T::from_f64(1.0 - self.decay));
+        let epsilon = T::from_f64(self.epsilon);
+        let mean_square = state[0].as_mut_slice();
+        let elements = parameters
+            .as_mut_slice()
+            .iter_mut()
+            .zip(gradient.as_slice());
+        for ((p, &g), s) in elements.zip(mean_square) {
+            *s = decay * *s + rest * g * g;
+            *p -= learning_rate * g / (s.sqrt() + epsilon);
+        }
+    }
+}
+
+// Adam (Kingma & Ba 2014): momentum and RMSProp with bias-corrected moments
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Adam {
+    pub learning_rate: f64,
+    pub beta1: f64,
+    pub beta2: f64,
+    pub epsilon: f64,
+}
+
+impl<T: Float> Optimizer<T> for Adam {
+    fn learning_rate(&self) -> f64 {
+        self.learning_rate
+    }
+
+    fn state_slots(&self) -> usize {
+        2
+    }
+
+    fn update(
+        &self,
+        parameters: &mut Matrix<T>,
+        gradient: &Matrix<T>,
+        state: &mut [Matrix<T>],
+        step: u64,
+    ) {
+        let step = step.max(1) as i32;
+        let correction1 = T::from_f64(1.0 - self.beta1.powi(step));
+        let correction2 = T::from_f64(1.0 - self.beta2.powi(step));
+        let learning_rate = T::from_f64(self.learning_rate);
+        let (beta1, rest1) = (T::from_f64(self.beta1), T::from_f64(1.0 - self.beta1));
+        let (beta2, rest2) = (T::from_f64(self.beta2), T::from_f64(1.0 - self.beta2));
+        let epsilon = T::from_f64(self.epsilon);
+        check_shapes(parameters, gradient, state);
+        let (first, second) = state.split_at_mut(1);
+        let moments = first[0]
+            .as_mut_slice()
+            .iter_mut()
+            .zip(second[0].as_mut_slice());
+        let elements = parameters
+            .as_mut_slice()
+            .iter_mut()
+            .zip(gradient.as_slice());
+        for ((p, &g), (m, v)) in elements.zip(moments) {
+            *m = beta1 * *m + rest1 * g;
+            *v = beta2 * *v + rest2 * g * g;
+            let m = *m / correction1;
+            let v = *v / correction2;
+            *p -= learning_rate * m / (v.sqrt() + epsilon);
+        }
+    }
+}
+
+// Which update rule a network trains with
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OptimizerConfig {
+    Sgd(Sgd),
+    Momentum(Momentum),
+    RmsProp(RmsProp),
+    Adam(Adam),
+}
+
+impl Default for OptimizerConfig {
+    fn default() -> Self {
+        OptimizerConfig::Sgd(Sgd {
+            learning_rate: 0.001,
+        })
+    }
+}
+
+impl OptimizerConfig {
+    pub fn optimizer<T: Float>(&self) -> &dyn Optimizer<T> {
+        match self {
+            OptimizerConfig::Sgd(sgd) => sgd,
+            OptimizerConfig::Momentum(momentum) => momentum,
+            OptimizerConfig::RmsProp(rms_prop) => rms_prop,
+            OptimizerConfig::Adam(adam) => adam,
+        }
+    }
+
+    pub fn with_learning_rate(self, learning_rate: f64) -> Self {
+        match self {
+            OptimizerConfig::Sgd(_) => OptimizerConfig::Sgd(Sgd { learning_rate }),
+            OptimizerConfig::Momentum(momentum) => OptimizerConfig::Momentum(Momentum {
+                learning_rate,
+                ..momentum
+            }),
+            OptimizerConfig::RmsProp(rms_prop) => OptimizerConfig::RmsProp(RmsProp {
+                learning_rate,
+                ..rms_prop
+            }),
+            OptimizerConfig::Adam(adam) => OptimizerConfig::Adam(Adam {
+                learning_rate,
+                ..adam
+            }),
+        }
+    }
+
+    pub fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        match *self {
+            OptimizerConfig::Sgd(Sgd { learning_rate }) => {
+                persist::write_u8(w, 0)?;
+                persist::write_f64(w, learning_rate)
+            }
+            OptimizerConfig::Momentum(Momentum {
+                learning_rate,
+                momentum,
+            }) => {
+                persist::write_u8(w, 1)?;
+                persist::write_f64(w, learning_rate)?;
+                persist::write_f64(w, momentum)
+            }
+            OptimizerConfig::RmsProp(RmsProp {
+                learning_rate,
+                decay,
+                epsilon,
+            }) => {
+                persist::write_u8(w, 2)?;
+                persist::write_f64(w, learning_rate)?;
+                persist::write_f64(w, decay)?;
+                persist::write_f64(w, epsilon)
+            }
+            OptimizerConfig::Adam(Adam {
+                learning_rate,
+                beta1,
+                beta2,
+                epsilon,
+            }) => {
+                persist::write_u8(w, 3)?;
+                persist::write_f64(w, learning_rate)?;
+                persist::write_f64(w, beta1)?;
+                persist::write_f64(w, beta2)?;
+                persist::write_f64(w, epsilon)
+            }
+        }
+    }
+
+    pub fn read_from<R: Read>(r: &mut R) -> io::Result<Self> {
+        Ok(match persist::read_u8(r)? {
+            0 => OptimizerConfig::Sgd(Sgd {
+                learning_rate: persist::read_f64(r)?,
+            }),
+            1 => OptimizerConfig::Momentum(Momentum {
+                learning_rate: persist::read_f64(r)?,
+                momentum: persist::read_f64(r)?,
+            }),
+            2 => OptimizerConfig::RmsProp(RmsProp {
+                learning_rate: persist::read_f64(r)?,
+                decay: persist::read_f64(r)?,
+                epsilon: persist::read_f64(r)?,
+            }),
+            3 => OptimizerConfig::Adam(Adam {
+                learning_rate: persist::read_f64(r)?,
+                beta1: persist::read_f64(r)?,
+                beta2: persist::read_f64(r)?,
+                epsilon: persist::read_f64(r)?,
+            }),
+            other => return Err(invalid_data(format!("invalid optimizer {}", other))),
+        })
+    }
+}
+
+// "sgd", "momentum", "rmsprop" or "adam" with the usual defaults
+impl FromStr for OptimizerConfig {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let learning_rate = 0.001;
+        match s.to_ascii_lowercase().as_str() {
+            "sgd" => Ok(OptimizerConfig::Sgd(Sgd { learning_rate })),
+            "momentum" => Ok(OptimizerConfig::Momentum(Momentum {
+                learning_rate,
+                momentum: 0.9,
+            })),
+            "rmsprop" => Ok(OptimizerConfig::RmsProp(RmsProp {
+                learning_rate,
+                decay: 0.9,
+                epsilon: 1e-8,
+            })),
+            "adam" => Ok(OptimizerConfig::Adam(Adam {
+                learning_rate,
+                beta1: 0.9,
+                beta2: 0.999,
+                epsilon: 1e-8,
+            })),
+            _ => Err(format!(
+                "unknown optimizer {:?}, expected sgd, momentum, rmsprop or adam",
+                s
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Runs `optimizer` from the parameters [1, -2] with one gradient per
+    // step; returns the parameters after each step and the final state
+    fn run<O: Optimizer>(optimizer: &O, gradients: &[[f64; 2]]) -> (Vec<Vec<f64>>, Vec<Matrix>) {
+        let mut parameters = Matrix::from_vec(1, 2, vec![1.0, -2.0]);
+        let mut state = vec![Matrix::new(1, 2); optimizer.state_slots()];
+        let mut steps = Vec::new();
+        for (step, gradient) in gradients.iter().enumerate() {
+            let gradient = Matrix::from_vec(1, 2, gradient.to_vec());
+            optimizer.update(&mut parameters, &gradient, &mut state, step as u64 + 1);
+            steps.push(parameters.as_slice().to_vec());
+        }
+        (steps, state)
+    }
+
+    fn assert_close(actual: &[f64], expected: &[f64]) {
+        assert_eq!(actual.len(), expected.len());
+        for (a, e) in actual.iter().zip(expected) {
+            assert!((a - e).abs() < 1e-12, "{:?} != {:?}", actual, expected);
+        }
+    }
+
+    #[test]
+    fn sgd_steps_against_the_gradient() {
+        let sgd = Sgd { learning_rate: 0.1 };
+        let (steps, state) = run(&sgd, &[[0.5, -1.0], [1.0, 2.0]]);
+        assert_close(&steps[0], &[0.95, -1.9]);
+        assert_close(&steps[1], &[0.85, -2.1]);
+        assert!(state.is_empty());
+    }
+
+    #[test]
+    fn momentum_keeps_a_decaying_sum_of_gradients() {
+        let momentum = Momentum {
+            learning_rate: 0.1,
+            momentum: 0.5,
+        };
+        let (steps, state) = run(&momentum, &[[0.5, -1.0], [1.0, 2.0], [0.0, 0.0]]);
+        // Velocity [0.5, -1], then [1.25, 1.5], then [0.625, 0.75]
+        assert_close(&steps[0], &[0.95, -1.9]);
+        assert_close(&steps[1], &[0.825, -2.05]);
+        assert_close(&steps[2], &[0.7625, -2.125]);
+        assert_close(state[0].as_slice(), &[0.625, 0.75]);
+    }
+
+    #[test]
+    fn rmsprop_divides_by_the_running_root_mean_square() {
+        let rms_prop = RmsProp {
+            learning_rate: 0.1,
+            decay: 0.75,
+            epsilon: 0.0,
+        };
+        let (steps, state) = run(&rms_prop, &[[2.0, -4.0], [1.0, 2.0], [0.0, 4.0]]);
+        // Mean squares [1, 4], again [1, 4], then [0.75, 7]
+        assert_close(&steps[0], &[0.8, -1.8]);
+        assert_close(&steps[1], &[0.7, -1.9]);
+        assert_close(&steps[2], &[0.7, -1.9 - 0.4 / 7f64.sqrt()]);
+        assert_close(state[0].as_slice(), &[0.75, 7.0]);
+    }
+
+    #[test]
+    fn adam_corrects_the_bias_of_its_moments() {
+        let adam = Adam {
+            learning_rate: 0.1,
+            beta1: 0.5,
+            beta2: 0.9375,
+            epsilon: 0.0,
+        };
+        // With a constant gradient the corrected moments are the gradient and
+        // its square at every step, so each step moves exactly the learning
+        // rate. Uncorrected, the first step would be twice as long.
+        let (steps, state) = run(&adam, &[[2.0, -4.0]; 3]);
+        assert_close(&steps[0], &[0.9, -1.9]);
+        assert_close(&steps[1], &[0.8, -1.8]);
+        assert_close(&steps[2], &[0.7, -1.7]);
+        let (first, second) = (1.0 - 0.5f64.powi(3), 1.0 - 0.9375f64.powi(3));
+        assert_close(state[0].as_slice(), &[2.0 * first, -4.0 * first]);
+        assert_close(state[1].as_slice(), &[4.0 * second, 16.0 * second]);
+
+        // Later steps see the moments of the gradient that changed
+        let (steps, _) = run(&adam, &[[2.0, -4.0], [0.0, 0.0]]);
+        let m = 0.5 / (1.0 - 0.25);
+        let v = (0.9375 * 0.25 / (1.0 - 0.9375f64.powi(2))).sqrt();
+        assert_close(&steps[1], &[0.9 - 0.1 * m / v, -1.9 + 0.1 * m / v]);
+    }
+}
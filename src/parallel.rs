@@ -0,0 +1,283 @@
+use crate::agent::Agent;
+use crate::env::Environment;
+use crate::float::Float;
+use crate::nn::NeuralNetwork;
+use crate::replay::Transition;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::{Arc, RwLock};
+use std::thread;
+
+pub struct ParallelConfig {
+    pub workers: usize,
+    // Stop once the agent has finished this many episodes across all workers,
+    // counting those from before a resume
+    pub episodes: usize,
+    // Publish fresh weights to the workers after this many learner updates
+    pub sync_every: usize,
+    // How many messages may queue up before workers block on the learner
+    pub queue_size: usize,
+    // Worker `i` explores with `seed + i`. Thread scheduling still decides the
+    // order transitions reach the learner, so parallel runs are not bit-identical.
+    pub seed: u64,
+}
+
+impl Default for ParallelConfig {
+    fn default() -> Self {
+        Self {
+            workers: 4,
+            episodes: 1000,
+            sync_every: 1,
+            queue_size: 10_000,
+            seed: 0,
+        }
+    }
+}
+
+pub struct EpisodeStats<E> {
+    // Worker thread, or environment slot when training in lockstep
+    pub worker: usize,
+    // Episodes the agent had finished before this one
+    pub episode: usize,
+    pub total_reward: f64,
+    pub steps: usize,
+    // The worker's exploration rate when the episode ended
+    pub epsilon: f64,
+    // How often each event happened, in order of first occurrence. Counted
+    // rather than listed since some events (e.g. starving) repeat every step.
+    pub events: Vec<(E, usize)>,
+}
+
+impl<E: PartialEq> EpisodeStats<E> {
+    pub(crate) fn new(worker: usize) -> Self {
+        Self {
+            worker,
+            episode: 0,
+            total_reward: 0.0,
+            steps: 0,
+            epsilon: 0.0,
+            events: Vec::new(),
+        }
+    }
+
+    pub(crate) fn record(&mut self, event: E) {
+        match self.events.iter_mut().find(|(seen, _)| *seen == event) {
+            Some((_, count)) => *count += 1,
+            None => self.events.push((event, 1)),
+        }
+    }
+
+    pub fn count(&self, event: &E) -> usize {
+        self.events
+            .iter()
+            .find(|(seen, _)| seen == event)
+            .map_or(0, |&(_, count)| count)
+    }
+}
+
+enum Message<E> {
+    Transition(usize, Transition),
+    EpisodeEnd(EpisodeStats<E>),
+}
+
+// Latest learner weights; workers pull a copy whenever `version` moves on
+struct SharedWeights<T: Float> {
+    version: AtomicUsize,
+    network: RwLock<NeuralNetwork<T>>,
+}
+
+impl<T: Float> SharedWeights<T> {
+    fn publish(&self, network: &NeuralNetwork<T>) {
+        *self.network.write().unwrap() = network.clone();
+        self.version.fetch_add(1, Ordering::Release);
+    }
+}
+
+// Self-play data collection: every worker thread plays its own environment
+// with a local copy of the network and streams transitions into the learner's
+// replay memory. The calling thread is the learner: it trains `agent` after
+// every finished episode and periodically sends the new weights back out.
+// Only the workers act, so the learner takes over their exploration rate and
+// counts their episodes in `agent.episodes`, to be saved with the agent.
+// `on_episode` sees every finished episode together with the learner's agent,
+// e.g. to log progress or write checkpoints.
+pub fn train<T, E, F, C>(
+    config: &ParallelConfig,
+    mut agent: Agent<T>,
+    make_env: F,
+    mut on_episode: C,
+) -> Agent<T>
+where
+    T: Float,
+    E: Environment,
+    E::Event: PartialEq + Send + 'static,
+    F: Fn(usize) -> E + Send + Sync + 'static,
+    C: FnMut(&EpisodeStats<E::Event>, &Agent<T>),
+{
+    let shared = Arc::new(SharedWeights {
+        version: AtomicUsize::new(0),
+        network: RwLock::new(agent.neural_network.clone()),
+    });
+    let stop = Arc::new(AtomicBool::new(false));
+    let make_env = Arc::new(make_env);
+    let (sender, receiver) = mpsc::sync_channel(config.queue_size);
+
+    let handles: Vec<_> = (0..config.workers)
+        .map(|worker| {
+            let shared = Arc::clone(&shared);
+            let stop = Arc::clone(&stop);
+            let make_env = Arc::clone(&make_env);
+            let sender = sender.clone();
+            let actor = agent.actor(config.seed.wrapping_add(worker as u64));
+            thread::spawn(move || {
+                run_worker(worker, make_env(worker), actor, &shared, &stop, sender)
+            })
+        })
+        .collect();
+    drop(sender);
+
+    learn(config, &mut agent, &shared, receiver, &mut on_episode);
+
+    stop.store(true, Ordering::Relaxed);
+    for handle in handles {
+        handle.join().expect("worker thread panicked");
+    }
+    agent
+}
+
+fn learn<T, E, C>(
+    config: &ParallelConfig,
+    agent: &mut Agent<T>,
+    shared: &SharedWeights<T>,
+    receiver: Receiver<Message<E>>,
+    on_episode: &mut C,
+) where
+    T: Float,
+    C: FnMut(&EpisodeStats<E>, &Agent<T>),
+{
+    let mut updates = 0;
+    while agent.episodes < config.episodes {
+        let Ok(message) = receiver.recv() else {
+            break;
+        };
+        match message {
+            Message::Transition(worker, t) => {
+                agent.remember_from(worker, &t.state, t.action, t.reward, &t.next_state, t.done);
+            }
+            Message::EpisodeEnd(mut stats) => {
+                stats.episode = agent.episodes;
+                agent.episodes += 1;
+                agent.epsilon = stats.epsilon;
+                on_episode(&stats, agent);
+                agent.train_long_memory();
+                updates += 1;
+                if updates % config.sync_every.max(1) == 0 {
+                    shared.publish(&agent.neural_network);
+                }
+            }
+        }
+    }
+}
+
+fn run_worker<T: Float, E: Environment>(
+    worker: usize,
+    mut env: E,
+    mut actor: Agent<T>,
+    shared: &SharedWeights<T>,
+    stop: &AtomicBool,
+    sender: SyncSender<Message<E::Event>>,
+) where
+    E::Event: PartialEq,
+{
+    let mut version = shared.version.load(Ordering::Acquire);
+    let mut state = env.reset();
+    let mut stats = EpisodeStats::new(worker);
+
+    while !stop.load(Ordering::Relaxed) {
+        let action = actor.select_action(&state);
+        let outcome = env.step(action);
+
+        stats.total_reward += outcome.reward;
+        stats.steps += 1;
+        for event in outcome.events {
+            stats.record(event);
+        }
+
+        let transition = Transition {
+            state,
+            action,
+            reward: outcome.reward,
+            discount: actor.gamma,
+            next_state: outcome.observation.clone(),
+            done: outcome.done,
+        };
+        // The learner hung up, so there is nobody left to collect for
+        if sender
+            .send(Message::Transition(worker, transition))
+            .is_err()
+        {
+            return;
+        }
+
+        if outcome.done {
+            stats.epsilon = actor.epsilon;
+            let finished = std::mem::replace(&mut stats, EpisodeStats::new(worker));
+            if sender.send(Message::EpisodeEnd(finished)).is_err() {
+                return;
+            }
+
+            let latest = shared.version.load(Ordering::Acquire);
+            if latest != version {
+                actor.neural_network = shared.network.read().unwrap().clone();
+                version = latest;
+            }
+            state = env.reset();
+        } else {
+            state = outcome.observation;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agent::AgentConfig;
+    use crate::game::{BoardConfig, Game};
+
+    // Trains until `agent` has finished `episodes` episodes and returns it
+    // with the number of episodes this call saw
+    fn play(agent: Agent, workers: usize, episodes: usize) -> (Agent, usize) {
+        let board = BoardConfig::new(8, 8);
+        let config = ParallelConfig {
+            workers,
+            episodes,
+            ..ParallelConfig::default()
+        };
+        let mut played = 0;
+        let agent = train(
+            &config,
+            agent,
+            move |worker| Game::with_seed(board, worker as u64),
+            |_, _| played += 1,
+        );
+        (agent, played)
+    }
+
+    #[test]
+    fn learner_keeps_the_workers_exploration_rate_and_episode_count() {
+        let config = AgentConfig {
+            memory_capacity: 1000,
+            batch_size: 16,
+            ..AgentConfig::default()
+        };
+        let (agent, played) = play(Agent::with_config(12, 4, 1, config), 2, 3);
+        assert_eq!((agent.episodes, played), (3, 3));
+        assert!(agent.epsilon < 1.0);
+
+        // A resumed run only plays the episodes still missing
+        let epsilon = agent.epsilon;
+        let (agent, played) = play(agent, 1, 5);
+        assert_eq!((agent.episodes, played), (5, 2));
+        assert!(agent.epsilon < epsilon);
+    }
+}
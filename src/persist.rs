@@ -0,0 +1,125 @@
+// Little-endian primitives shared by the model and checkpoint file formats
+use std::io::{self, Read, Write};
+
+pub fn invalid_data(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+pub fn write_magic<W: Write>(w: &mut W, magic: &[u8; 4], version: u32) -> io::Result<()> {
+    w.write_all(magic)?;
+    write_u32(w, version)
+}
+
+// Checks the magic bytes and returns the format version
+pub fn read_magic<R: Read>(r: &mut R, magic: &[u8; 4]) -> io::Result<u32> {
+    let mut found = [0u8; 4];
+    r.read_exact(&mut found)?;
+    if &found != magic {
+        return Err(invalid_data(format!(
+            "not a {} file",
+            String::from_utf8_lossy(magic)
+        )));
+    }
+    read_u32(r)
+}
+
+pub fn write_u8<W: Write>(w: &mut W, value: u8) -> io::Result<()> {
+    w.write_all(&[value])
+}
+
+pub fn read_u8<R: Read>(r: &mut R) -> io::Result<u8> {
+    let mut bytes = [0u8; 1];
+    r.read_exact(&mut bytes)?;
+    Ok(bytes[0])
+}
+
+pub fn write_u32<W: Write>(w: &mut W, value: u32) -> io::Result<()> {
+    w.write_all(&value.to_le_bytes())
+}
+
+pub fn read_u32<R: Read>(r: &mut R) -> io::Result<u32> {
+    let mut bytes = [0u8; 4];
+    r.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+pub fn write_u64<W: Write>(w: &mut W, value: u64) -> io::Result<()> {
+    w.write_all(&value.to_le_bytes())
+}
+
+pub fn read_u64<R: Read>(r: &mut R) -> io::Result<u64> {
+    let mut bytes = [0u8; 8];
+    r.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+pub fn write_f32<W: Write>(w: &mut W, value: f32) -> io::Result<()> {
+    w.write_all(&value.to_le_bytes())
+}
+
+pub fn read_f32<R: Read>(r: &mut R) -> io::Result<f32> {
+    let mut bytes = [0u8; 4];
+    r.read_exact(&mut bytes)?;
+    Ok(f32::from_le_bytes(bytes))
+}
+
+pub fn write_f64<W: Write>(w: &mut W, value: f64) -> io::Result<()> {
+    w.write_all(&value.to_le_bytes())
+}
+
+pub fn read_f64<R: Read>(r: &mut R) -> io::Result<f64> {
+    let mut bytes = [0u8; 8];
+    r.read_exact(&mut bytes)?;
+    Ok(f64::from_le_bytes(bytes))
+}
+
+pub fn write_usize<W: Write>(w: &mut W, value: usize) -> io::Result<()> {
+    write_u64(w, value as u64)
+}
+
+pub fn read_usize<R: Read>(r: &mut R) -> io::Result<usize> {
+    usize::try_from(read_u64(r)?).map_err(|_| invalid_data("length does not fit in memory"))
+}
+
+pub fn write_i32<W: Write>(w: &mut W, value: i32) -> io::Result<()> {
+    w.write_all(&value.to_le_bytes())
+}
+
+pub fn read_i32<R: Read>(r: &mut R) -> io::Result<i32> {
+    let mut bytes = [0u8; 4];
+    r.read_exact(&mut bytes)?;
+    Ok(i32::from_le_bytes(bytes))
+}
+
+pub fn write_bool<W: Write>(w: &mut W, value: bool) -> io::Result<()> {
+    write_u8(w, value as u8)
+}
+
+pub fn read_bool<R: Read>(r: &mut R) -> io::Result<bool> {
+    match read_u8(r)? {
+        0 => Ok(false),
+        1 => Ok(true),
+        other => Err(invalid_data(format!("invalid bool {}", other))),
+    }
+}
+
+// Length-prefixed list of f64 values
+pub fn write_f64s<W: Write>(w: &mut W, values: &[f64]) -> io::Result<()> {
+    write_usize(w, values.len())?;
+    values.iter().try_for_each(|&value| write_f64(w, value))
+}
+
+pub fn read_f64s<R: Read>(r: &mut R) -> io::Result<Vec<f64>> {
+    let len = read_usize(r)?;
+    (0..len).map(|_| read_f64(r)).collect()
+}
+
+pub fn check_version(kind: &str, found: u32, expected: u32) -> io::Result<()> {
+    if found != expected {
+        return Err(invalid_data(format!(
+            "unsupported {} version {} (expected {})",
+            kind, found, expected
+        )));
+    }
+    Ok(())
+}
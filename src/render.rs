@@ -1,132 +1,158 @@
-use crate::game::{Game, TILE_SIZE };
 use macroquad::prelude::*;
+use rusty_snake::game::{BoardConfig, Cell, Game};
 
-const GAME_WIDTH: f32 = 400.0;
-const GAME_HEIGHT: f32 = 400.0;
+// Cell size the window is opened for; cells are scaled to fit the window
+// actually on screen
+const TILE_SIZE: f32 = 10.0;
 
 const START_X: f32 = 10.0;
 const START_Y: f32 = 10.0;
 
-impl Game {
-    pub fn draw_borders(&self) {
-        let border_thickness = 5.0;
-
-        // Draw top border
-        draw_rectangle(
-            START_X - border_thickness,
-            START_Y - border_thickness,
-            GAME_WIDTH + 2.0 * border_thickness,
-            border_thickness,
-            WHITE,
-        );
-        // Draw bottom border
-        draw_rectangle(
-            START_X - border_thickness,
-            START_Y + GAME_HEIGHT,
-            GAME_WIDTH + 2.0 * border_thickness,
-            border_thickness,
-            WHITE,
-        );
-        // Draw left border
-        draw_rectangle(
-            START_X - border_thickness,
-            START_Y - border_thickness,
-            border_thickness,
-            GAME_HEIGHT + 2.0 * border_thickness,
-            WHITE,
-        );
-        // Draw right border
-        draw_rectangle(
-            START_X + GAME_WIDTH,
-            START_Y - border_thickness,
-            border_thickness,
-            GAME_HEIGHT + 2.0 * border_thickness,
-            WHITE,
-        );
-    }
+// Strip under the board that holds the score line
+const HUD_HEIGHT: f32 = 30.0;
 
-    pub fn draw(&self) {
-        for segment in &self.snake {
-            draw_rectangle(segment.x, segment.y, TILE_SIZE, TILE_SIZE, WHITE);
-        }
+// Wide enough for the score line, small enough for most screens
+const MIN_WINDOW_SIZE: (f32, f32) = (420.0, 300.0);
+const MAX_WINDOW_SIZE: (f32, f32) = (1200.0, 900.0);
 
-        draw_rectangle(self.food.x, self.food.y, TILE_SIZE, TILE_SIZE, GREEN);
-    }
+// Window size in pixels that shows `board` at `TILE_SIZE`, within the limits
+// above
+pub fn window_size(board: BoardConfig) -> (i32, i32) {
+    let width = board.width as f32 * TILE_SIZE + 2.0 * START_X;
+    let height = board.height as f32 * TILE_SIZE + 2.0 * START_Y + HUD_HEIGHT;
+    (
+        width.clamp(MIN_WINDOW_SIZE.0, MAX_WINDOW_SIZE.0) as i32,
+        height.clamp(MIN_WINDOW_SIZE.1, MAX_WINDOW_SIZE.1) as i32,
+    )
+}
 
-    pub fn game_over(&self) {
-        let game_over_text = &format!("Game Over! Score: {}", self.score);
-        let restart_text = "Press SPACE to restart the game";
-
-        let game_over_width = measure_text(game_over_text, None, 34, 1.0).width;
-        let restart_width = measure_text(restart_text, None, 22, 1.0).width;
-
-        let center_x = START_X + GAME_WIDTH / 2.0;
-        let center_y = START_Y + GAME_HEIGHT / 2.0;
-
-        draw_text(
-            game_over_text,
-            center_x - game_over_width / 2.0,
-            center_y - 20.0,
-            34.0,
-            RED,
-        );
-        draw_text(
-            restart_text,
-            center_x - restart_width / 2.0,
-            center_y + 30.0,
-            22.0,
-            WHITE,
-        );
-    }
-    pub fn start_game(&self) {
-        let start_text = "Press SPACE to start";
-        let start_width = measure_text(start_text, None, 34, 1.0).width;
-
-        let center_x = START_X + GAME_WIDTH / 2.0;
-        let center_y = START_Y + GAME_HEIGHT / 2.0;
-
-        draw_text(
-            start_text,
-            center_x - start_width / 2.0,
-            center_y - 20.0,
-            34.0,
-            WHITE,
-        );
-    }
+// Largest cell size that fits the whole board and the score line on screen
+fn tile_size(game: &Game) -> f32 {
+    let width = (screen_width() - 2.0 * START_X) / game.board.width as f32;
+    let height = (screen_height() - 2.0 * START_Y - HUD_HEIGHT) / game.board.height as f32;
+    width.min(height)
+}
 
-    pub fn score_counter(&self) {
-        let mut score_text = "Score: ".to_string();
-        score_text = format!("{}{}", score_text, &self.score);
-        draw_text(
-            &score_text,
-            300.0,
-            30.0,
-            18.0,
-            WHITE,
-        );
-    }
+fn draw_cell(cell: Cell, tile_size: f32, color: Color) {
+    draw_rectangle(
+        START_X + cell.x as f32 * tile_size,
+        START_Y + cell.y as f32 * tile_size,
+        tile_size,
+        tile_size,
+        color,
+    );
+}
+
+// Size of the playing field in pixels
+fn game_size(game: &Game) -> (f32, f32) {
+    let tile_size = tile_size(game);
+    (
+        game.board.width as f32 * tile_size,
+        game.board.height as f32 * tile_size,
+    )
+}
+
+pub fn draw_borders(game: &Game) {
+    let border_thickness = 5.0;
+    let (game_width, game_height) = game_size(game);
+
+    // Draw top border
+    draw_rectangle(
+        START_X - border_thickness,
+        START_Y - border_thickness,
+        game_width + 2.0 * border_thickness,
+        border_thickness,
+        WHITE,
+    );
+    // Draw bottom border
+    draw_rectangle(
+        START_X - border_thickness,
+        START_Y + game_height,
+        game_width + 2.0 * border_thickness,
+        border_thickness,
+        WHITE,
+    );
+    // Draw left border
+    draw_rectangle(
+        START_X - border_thickness,
+        START_Y - border_thickness,
+        border_thickness,
+        game_height + 2.0 * border_thickness,
+        WHITE,
+    );
+    // Draw right border
+    draw_rectangle(
+        START_X + game_width,
+        START_Y - border_thickness,
+        border_thickness,
+        game_height + 2.0 * border_thickness,
+        WHITE,
+    );
+}
 
-    pub fn high_score(&self) {
-        let mut score_text = "High score: ".to_string();
-        score_text = format!("{}{}", score_text, &self.high_score);
-        draw_text(
-            &score_text,
-            450.0,
-            30.0,
-            18.0,
-            WHITE,
-        );
+pub fn draw(game: &Game) {
+    let tile_size = tile_size(game);
+    for segment in &game.snake {
+        draw_cell(*segment, tile_size, WHITE);
     }
 
-    pub fn n_games(&self) {
-        let mut score_text = "N of Games: ".to_string();
-        score_text = format!("{}{}", score_text, &self.n_games);
-        draw_text(
-            &score_text,
-            580.0,
-            30.0,
-            18.0,
-            WHITE,
-        );
+    draw_cell(game.food, tile_size, GREEN);
+}
+
+pub fn game_over(game: &Game) {
+    let game_over_text = &format!("Game Over! Score: {}", game.score);
+    let restart_text = "Press SPACE to restart the game";
+
+    let game_over_width = measure_text(game_over_text, None, 34, 1.0).width;
+    let restart_width = measure_text(restart_text, None, 22, 1.0).width;
+
+    let (game_width, game_height) = game_size(game);
+    let center_x = START_X + game_width / 2.0;
+    let center_y = START_Y + game_height / 2.0;
+
+    draw_text(
+        game_over_text,
+        center_x - game_over_width / 2.0,
+        center_y - 20.0,
+        34.0,
+        RED,
+    );
+    draw_text(
+        restart_text,
+        center_x - restart_width / 2.0,
+        center_y + 30.0,
+        22.0,
+        WHITE,
+    );
+}
+pub fn start_game(game: &Game) {
+    let start_text = "Press SPACE to start";
+    let start_width = measure_text(start_text, None, 34, 1.0).width;
+
+    let (game_width, game_height) = game_size(game);
+    let center_x = START_X + game_width / 2.0;
+    let center_y = START_Y + game_height / 2.0;
+
+    draw_text(
+        start_text,
+        center_x - start_width / 2.0,
+        center_y - 20.0,
+        34.0,
+        WHITE,
+    );
+}
+
+// Score, high score and number of games, in a row under the board
+pub fn hud(game: &Game) {
+    let (_, game_height) = game_size(game);
+    let y = START_Y + game_height + HUD_HEIGHT - 8.0;
+    let mut x = START_X;
+    for text in [
+        format!("Score: {}", game.score),
+        format!("High score: {}", game.high_score),
+        format!("N of Games: {}", game.n_games),
+    ] {
+        draw_text(&text, x, y, 18.0, WHITE);
+        x += measure_text(&text, None, 18, 1.0).width + 30.0;
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,984 @@
+use crate::persist::{self, invalid_data};
+use rand::Rng;
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+
+const MEMORY_MAGIC: &[u8; 4] = b"RSMM";
+const MEMORY_VERSION: u32 = 2;
+
+// Largest memory `read_from` accepts, in stored observation features
+// (capacity times observation size), so a corrupt header can't make it
+// allocate more than a few hundred megabytes
+const MAX_STORED_FEATURES: usize = 1 << 26;
+
+// One transition read back out of a `ReplayBuffer`. Reading into the same
+// value again reuses its vectors, so a training loop allocates only once.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Transition {
+    pub state: Vec<f64>,
+    pub action: usize,
+    pub reward: f64,
+    // Weight of the value bootstrapped from `next_state`: gamma to the power
+    // of the steps `reward` covers
+    pub discount: f64,
+    pub next_state: Vec<f64>,
+    pub done: bool,
+}
+
+// Observations are kept as bits while every feature seen so far is 0 or 1
+// (as with the Snake danger/direction/food flags) and as plain f64s from the
+// first one that is not. Each slot holds two: the state and the next state.
+#[derive(Clone, Debug)]
+enum Observations {
+    Bits { words: usize, data: Vec<u64> },
+    Dense(Vec<f64>),
+}
+
+impl Observations {
+    fn bits(count: usize, size: usize) -> Self {
+        let words = size.div_ceil(64);
+        Observations::Bits {
+            words,
+            data: vec![0; count * words],
+        }
+    }
+
+    fn is_binary(observation: &[f64]) -> bool {
+        observation.iter().all(|&x| x == 0.0 || x == 1.0)
+    }
+
+    fn write(&mut self, index: usize, observation: &[f64]) {
+        match self {
+            Observations::Bits { words, data } => {
+                let packed = &mut data[index * *words..(index + 1) * *words];
+                packed.fill(0);
+                for (i, &x) in observation.iter().enumerate() {
+                    if x == 1.0 {
+                        packed[i / 64] |= 1 << (i % 64);
+                    }
+                }
+            }
+            Observations::Dense(data) => {
+                let size = observation.len();
+                data[index * size..(index + 1) * size].copy_from_slice(observation);
+            }
+        }
+    }
+
+    fn read_into(&self, index: usize, size: usize, out: &mut Vec<f64>) {
+        out.clear();
+        match self {
+            Observations::Bits { words, data } => {
+                let packed = &data[index * words..(index + 1) * words];
+                out.extend((0..size).map(|i| ((packed[i / 64] >> (i % 64)) & 1) as f64));
+            }
+            Observations::Dense(data) => {
+                out.extend_from_slice(&data[index * size..(index + 1) * size]);
+            }
+        }
+    }
+
+    // Unpacks the first `used` observations once a non-binary one turns up
+    fn to_dense(&self, count: usize, size: usize, used: usize) -> Self {
+        let mut dense = vec![0.0; count * size];
+        let mut observation = Vec::with_capacity(size);
+        for index in 0..used {
+            self.read_into(index, size, &mut observation);
+            dense[index * size..(index + 1) * size].copy_from_slice(&observation);
+        }
+        Observations::Dense(dense)
+    }
+}
+
+// Fixed-capacity ring of transitions. All storage is allocated up front and
+// the oldest slot is overwritten once it is full.
+#[derive(Clone, Debug)]
+pub struct ReplayBuffer {
+    capacity: usize,
+    observation_size: usize,
+    observations: Observations,
+    actions: Vec<usize>,
+    rewards: Vec<f64>,
+    discounts: Vec<f64>,
+    dones: Vec<bool>,
+    len: usize,
+    // Slot the next transition goes into
+    next: usize,
+}
+
+impl ReplayBuffer {
+    pub fn new(capacity: usize, observation_size: usize) -> Self {
+        assert!(
+            capacity > 0,
+            "ReplayBuffer needs room for at least one transition"
+        );
+        Self {
+            capacity,
+            observation_size,
+            observations: Observations::bits(2 * capacity, observation_size),
+            actions: vec![0; capacity],
+            rewards: vec![0.0; capacity],
+            discounts: vec![0.0; capacity],
+            dones: vec![false; capacity],
+            len: 0,
+            next: 0,
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn observation_size(&self) -> usize {
+        self.observation_size
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn clear(&mut self) {
+        self.observations = Observations::bits(2 * self.capacity, self.observation_size);
+        self.len = 0;
+        self.next = 0;
+    }
+
+    // Stores a transition and returns the slot it went into
+    pub fn push(
+        &mut self,
+        state: &[f64],
+        action: usize,
+        reward: f64,
+        discount: f64,
+        next_state: &[f64],
+        done: bool,
+    ) -> usize {
+        assert!(
+            state.len() == self.observation_size && next_state.len() == self.observation_size,
+            "observation size does not match the replay buffer"
+        );
+        if matches!(self.observations, Observations::Bits { .. })
+            && !(Observations::is_binary(state) && Observations::is_binary(next_state))
+        {
+            self.observations =
+                self.observations
+                    .to_dense(2 * self.capacity, self.observation_size, 2 * self.len);
+        }
+
+        let slot = self.next;
+        self.observations.write(2 * slot, state);
+        self.observations.write(2 * slot + 1, next_state);
+        self.actions[slot] = action;
+        self.rewards[slot] = reward;
+        self.discounts[slot] = discount;
+        self.dones[slot] = done;
+        self.len = (self.len + 1).min(self.capacity);
+        self.next = (slot + 1) % self.capacity;
+        slot
+    }
+
+    // Copies the transition in `slot` into `out` without allocating once
+    // `out` has held an observation before
+    pub fn read_into(&self, slot: usize, out: &mut Transition) {
+        assert!(slot < self.len, "slot {} is empty", slot);
+        let size = self.observation_size;
+        self.observations.read_into(2 * slot, size, &mut out.state);
+        self.observations
+            .read_into(2 * slot + 1, size, &mut out.next_state);
+        out.action = self.actions[slot];
+        out.reward = self.rewards[slot];
+        out.discount = self.discounts[slot];
+        out.done = self.dones[slot];
+    }
+
+    pub fn get(&self, slot: usize) -> Transition {
+        let mut transition = Transition::default();
+        self.read_into(slot, &mut transition);
+        transition
+    }
+
+    // Oldest to newest
+    pub fn iter(&self) -> impl Iterator<Item = Transition> + '_ {
+        let start = if self.len == self.capacity {
+            self.next
+        } else {
+            0
+        };
+        (0..self.len).map(move |i| self.get((start + i) % self.capacity))
+    }
+
+    fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        persist::write_usize(w, self.capacity)?;
+        persist::write_usize(w, self.observation_size)?;
+        persist::write_usize(w, self.next)?;
+        persist::write_usize(w, self.len)?;
+        match &self.observations {
+            Observations::Bits { words, data } => {
+                persist::write_u8(w, 0)?;
+                for &word in &data[..2 * self.len * words] {
+                    persist::write_u64(w, word)?;
+                }
+            }
+            Observations::Dense(data) => {
+                persist::write_u8(w, 1)?;
+                for &x in &data[..2 * self.len * self.observation_size] {
+                    persist::write_f64(w, x)?;
+                }
+            }
+        }
+        for slot in 0..self.len {
+            persist::write_usize(w, self.actions[slot])?;
+            persist::write_f64(w, self.rewards[slot])?;
+            persist::write_f64(w, self.discounts[slot])?;
+            persist::write_bool(w, self.dones[slot])?;
+        }
+        Ok(())
+    }
+
+    fn read_from<R: Read>(r: &mut R) -> io::Result<Self> {
+        let capacity = persist::read_usize(r)?;
+        let observation_size = persist::read_usize(r)?;
+        let next = persist::read_usize(r)?;
+        let len = persist::read_usize(r)?;
+        if capacity == 0 || len > capacity || next >= capacity {
+            return Err(invalid_data(format!(
+                "replay memory of {} transitions does not fit capacity {}",
+                len, capacity
+            )));
+        }
+        // Until the ring wraps around, transitions fill it from slot 0
+        if len < capacity && next != len {
+            return Err(invalid_data(format!(
+                "replay memory of {} transitions cannot continue at slot {}",
+                len, next
+            )));
+        }
+        let features = capacity.checked_mul(observation_size);
+        if observation_size == 0 || features.is_none_or(|n| n > MAX_STORED_FEATURES) {
+            return Err(invalid_data(format!(
+                "invalid replay memory of {} observations of size {}",
+                capacity, observation_size
+            )));
+        }
+        let mut buffer = Self::new(capacity, observation_size);
+        match persist::read_u8(r)? {
+            0 => {
+                if let Observations::Bits { words, data } = &mut buffer.observations {
+                    for word in &mut data[..2 * len * *words] {
+                        *word = persist::read_u64(r)?;
+                    }
+                }
+            }
+            1 => {
+                let mut data = vec![0.0; 2 * capacity * observation_size];
+                for x in &mut data[..2 * len * observation_size] {
+                    *x = persist::read_f64(r)?;
+                }
+                buffer.observations = Observations::Dense(data);
+            }
+            other => {
+                return Err(invalid_data(format!(
+                    "invalid observation encoding {}",
+                    other
+                )))
+            }
+        }
+        for slot in 0..len {
+            buffer.actions[slot] = persist::read_usize(r)?;
+            buffer.rewards[slot] = persist::read_f64(r)?;
+            let discount = persist::read_f64(r)?;
+            if !discount.is_finite() || discount < 0.0 {
+                return Err(invalid_data(format!(
+                    "invalid discount {} in slot {}",
+                    discount, slot
+                )));
+            }
+            buffer.discounts[slot] = discount;
+            buffer.dones[slot] = persist::read_bool(r)?;
+        }
+        buffer.len = len;
+        buffer.next = next;
+        Ok(buffer)
+    }
+}
+
+// Binary tree where every parent holds the sum of its children, so sampling
+// proportionally to the leaf values and updating one leaf are both O(log n).
+// Leaves live at `capacity..2 * capacity`, the root at index 1.
+pub struct SumTree {
+    capacity: usize,
+    nodes: Vec<f64>,
+}
+
+impl SumTree {
+    pub fn new(capacity: usize) -> Self {
+        assert!(capacity > 0, "SumTree needs room for at least one value");
+        Self {
+            capacity,
+            nodes: vec![0.0; 2 * capacity],
+        }
+    }
+
+    pub fn total(&self) -> f64 {
+        self.nodes[1]
+    }
+
+    pub fn get(&self, index: usize) -> f64 {
+        self.nodes[self.capacity + index]
+    }
+
+    pub fn update(&mut self, index: usize, value: f64) {
+        let mut node = self.capacity + index;
+        self.nodes[node] = value;
+        while node > 1 {
+            node /= 2;
+            self.nodes[node] = self.nodes[2 * node] + self.nodes[2 * node + 1];
+        }
+    }
+
+    // Leaf whose cumulative range contains `value`, for `value` in `0..total()`
+    pub fn find(&self, mut value: f64) -> usize {
+        let mut node = 1;
+        while node < self.capacity {
+            let left = 2 * node;
+            // Rounding can push `value` past the last non-empty leaf; never
+            // walk into an empty subtree because of it
+            if value < self.nodes[left] || self.nodes[left + 1] == 0.0 {
+                node = left;
+            } else {
+                value -= self.nodes[left];
+                node = left + 1;
+            }
+        }
+        node - self.capacity
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PrioritizedConfig {
+    // How strongly priorities skew sampling; 0 samples uniformly
+    pub alpha: f64,
+    // Importance-sampling correction at the start of training
+    pub beta: f64,
+    // Number of sampled batches over which beta is annealed up to 1
+    pub beta_annealing_steps: u64,
+    // Keeps transitions with zero TD error sampleable
+    pub epsilon: f64,
+}
+
+impl Default for PrioritizedConfig {
+    fn default() -> Self {
+        Self {
+            alpha: 0.6,
+            beta: 0.4,
+            beta_annealing_steps: 5_000,
+            epsilon: 1e-3,
+        }
+    }
+}
+
+// A sampled batch: slots in the buffer and the importance-sampling weight of
+// each, scaled so the largest weight is 1. Kept between batches so sampling
+// reuses its vectors.
+#[derive(Clone, Debug, Default)]
+pub struct Sample {
+    pub indices: Vec<usize>,
+    pub weights: Vec<f64>,
+}
+
+// Prioritized experience replay (Schaul et al. 2015): transitions are
+// sampled in proportion to |TD error|^alpha and their updates weighted by
+// (N * P(i))^-beta to undo the bias that introduces.
+pub struct PrioritizedReplay {
+    pub config: PrioritizedConfig,
+    beta: f64,
+    buffer: ReplayBuffer,
+    tree: SumTree,
+    max_priority: f64,
+}
+
+impl PrioritizedReplay {
+    pub fn new(capacity: usize, observation_size: usize, config: PrioritizedConfig) -> Self {
+        Self {
+            config,
+            beta: config.beta,
+            buffer: ReplayBuffer::new(capacity, observation_size),
+            tree: SumTree::new(capacity),
+            max_priority: 1.0,
+        }
+    }
+
+    pub fn buffer(&self) -> &ReplayBuffer {
+        &self.buffer
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.buffer.capacity()
+    }
+
+    pub fn len(&self) -> usize {
+        self.buffer.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buffer.is_empty()
+    }
+
+    pub fn beta(&self) -> f64 {
+        self.beta
+    }
+
+    // Empties the buffer but keeps the annealed beta
+    pub fn clear(&mut self) {
+        self.buffer.clear();
+        self.tree = SumTree::new(self.buffer.capacity());
+        self.max_priority = 1.0;
+    }
+
+    pub fn read_into(&self, slot: usize, out: &mut Transition) {
+        self.buffer.read_into(slot, out);
+    }
+
+    // New transitions get the highest priority seen so far so each one is
+    // sampled at least once before its TD error is known
+    pub fn push(
+        &mut self,
+        state: &[f64],
+        action: usize,
+        reward: f64,
+        discount: f64,
+        next_state: &[f64],
+        done: bool,
+    ) {
+        let slot = self
+            .buffer
+            .push(state, action, reward, discount, next_state, done);
+        self.tree
+            .update(slot, self.max_priority.powf(self.config.alpha));
+    }
+
+    // Stratified sampling: the priority mass is cut into `batch_size` equal
+    // segments and one transition is drawn from each
+    pub fn sample_into<R: Rng>(&mut self, batch_size: usize, rng: &mut R, out: &mut Sample) {
+        assert!(
+            !self.buffer.is_empty(),
+            "cannot sample from an empty replay memory"
+        );
+        let total = self.tree.total();
+        let segment = total / batch_size as f64;
+        let last = self.buffer.len() - 1;
+        out.indices.clear();
+        out.indices.extend((0..batch_size).map(|i| {
+            let value = (i as f64 + rng.gen::<f64>()) * segment;
+            self.tree.find(value.min(total)).min(last)
+        }));
+
+        let len = self.buffer.len() as f64;
+        out.weights.clear();
+        out.weights.extend(out.indices.iter().map(|&index| {
+            let probability = self.tree.get(index) / total;
+            (len * probability).powf(-self.beta)
+        }));
+        let max_weight = out.weights.iter().cloned().fold(0.0, f64::max);
+        if max_weight > 0.0 {
+            out.weights
+                .iter_mut()
+                .for_each(|weight| *weight /= max_weight);
+        }
+
+        let annealing_steps = self.config.beta_annealing_steps.max(1) as f64;
+        self.beta = (self.beta + (1.0 - self.config.beta) / annealing_steps).min(1.0);
+    }
+
+    pub fn sample<R: Rng>(&mut self, batch_size: usize, rng: &mut R) -> Sample {
+        let mut sample = Sample::default();
+        self.sample_into(batch_size, rng, &mut sample);
+        sample
+    }
+
+    pub fn update_priority(&mut self, slot: usize, td_error: f64) {
+        let priority = td_error.abs() + self.config.epsilon;
+        self.max_priority = self.max_priority.max(priority);
+        self.tree.update(slot, priority.powf(self.config.alpha));
+    }
+
+    // The exact slot layout and priorities are kept so a resumed run samples
+    // the same transitions as the original would have
+    pub fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        persist::write_magic(w, MEMORY_MAGIC, MEMORY_VERSION)?;
+        persist::write_f64(w, self.config.alpha)?;
+        persist::write_f64(w, self.config.beta)?;
+        persist::write_u64(w, self.config.beta_annealing_steps)?;
+        persist::write_f64(w, self.config.epsilon)?;
+        persist::write_f64(w, self.beta)?;
+        persist::write_f64(w, self.max_priority)?;
+        self.buffer.write_to(w)?;
+        for slot in 0..self.buffer.len() {
+            persist::write_f64(w, self.tree.get(slot))?;
+        }
+        Ok(())
+    }
+
+    // Replaces the contents with those stored by `write_to`; the capacity
+    // comes from the file
+    pub fn read_from<R: Read>(&mut self, r: &mut R) -> io::Result<()> {
+        let version = persist::read_magic(r, MEMORY_MAGIC)?;
+        persist::check_version("replay memory", version, MEMORY_VERSION)?;
+
+        let config = PrioritizedConfig {
+            alpha: persist::read_f64(r)?,
+            beta: persist::read_f64(r)?,
+            beta_annealing_steps: persist::read_u64(r)?,
+            epsilon: persist::read_f64(r)?,
+        };
+        let beta = persist::read_f64(r)?;
+        let max_priority = persist::read_f64(r)?;
+        let buffer = ReplayBuffer::read_from(r)?;
+        let mut tree = SumTree::new(buffer.capacity());
+        for slot in 0..buffer.len() {
+            let priority = persist::read_f64(r)?;
+            if !priority.is_finite() || priority < 0.0 {
+                return Err(invalid_data(format!(
+                    "invalid priority {} in slot {}",
+                    priority, slot
+                )));
+            }
+            tree.update(slot, priority);
+        }
+        *self = Self {
+            config,
+            beta,
+            buffer,
+            tree,
+            max_priority,
+        };
+        Ok(())
+    }
+}
+
+// Turns single steps into n-step transitions: the discounted rewards of up to
+// `n` steps, bootstrapping from the state `n` steps on. At `done` every
+// pending step is flushed with the rewards that remain and no bootstrap, so
+// returns never reach across a reset. Steps from different environments must
+// go through different accumulators.
+#[derive(Clone, Debug)]
+pub struct NStepAccumulator {
+    n: usize,
+    gamma: f64,
+    // (state, action, reward) of the steps still waiting for their return
+    pending: VecDeque<(Vec<f64>, usize, f64)>,
+}
+
+impl NStepAccumulator {
+    pub fn new(n: usize, gamma: f64) -> Self {
+        assert!(n > 0, "n-step returns need at least one step");
+        Self {
+            n,
+            gamma,
+            pending: VecDeque::with_capacity(n),
+        }
+    }
+
+    pub fn n(&self) -> usize {
+        self.n
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    // States of the steps still waiting for their return, oldest first
+    pub fn pending_states(&self) -> impl Iterator<Item = &[f64]> {
+        self.pending.iter().map(|(state, _, _)| state.as_slice())
+    }
+
+    // Adds a step and stores every transition it completes in `memory`
+    pub fn push(
+        &mut self,
+        state: &[f64],
+        action: usize,
+        reward: f64,
+        next_state: &[f64],
+        done: bool,
+        memory: &mut PrioritizedReplay,
+    ) {
+        self.pending.push_back((state.to_vec(), action, reward));
+        if done {
+            while !self.pending.is_empty() {
+                self.emit(next_state, true, memory);
+            }
+        } else if self.pending.len() == self.n {
+            self.emit(next_state, false, memory);
+        }
+    }
+
+    // Stores the oldest pending step with the rewards collected since, and
+    // the discount for however many steps that was
+    fn emit(&mut self, next_state: &[f64], done: bool, memory: &mut PrioritizedReplay) {
+        let n_step_return = self
+            .pending
+            .iter()
+            .rev()
+            .fold(0.0, |tail, &(_, _, reward)| reward + self.gamma * tail);
+        let discount = self.gamma.powi(self.pending.len() as i32);
+        let (state, action, _) = self.pending.pop_front().unwrap();
+        memory.push(&state, action, n_step_return, discount, next_state, done);
+    }
+
+    pub fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        persist::write_usize(w, self.n)?;
+        persist::write_f64(w, self.gamma)?;
+        persist::write_usize(w, self.pending.len())?;
+        for (state, action, reward) in &self.pending {
+            persist::write_f64s(w, state)?;
+            persist::write_usize(w, *action)?;
+            persist::write_f64(w, *reward)?;
+        }
+        Ok(())
+    }
+
+    pub fn read_from<R: Read>(r: &mut R) -> io::Result<Self> {
+        let n = persist::read_usize(r)?;
+        let gamma = persist::read_f64(r)?;
+        let len = persist::read_usize(r)?;
+        if n == 0 || len >= n {
+            return Err(invalid_data(format!(
+                "{} pending steps do not fit {}-step returns",
+                len, n
+            )));
+        }
+        let mut accumulator = Self::new(n, gamma);
+        for _ in 0..len {
+            accumulator.pending.push_back((
+                persist::read_f64s(r)?,
+                persist::read_usize(r)?,
+                persist::read_f64(r)?,
+            ));
+        }
+        Ok(accumulator)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rng;
+
+    // Binary observation with the bits of `i` repeated in every byte,
+    // distinct for every i below 2^min(size, 8)
+    fn bits(i: usize, size: usize) -> Vec<f64> {
+        (0..size).map(|bit| ((i >> (bit % 8)) & 1) as f64).collect()
+    }
+
+    fn push_step(buffer: &mut ReplayBuffer, i: usize) {
+        let size = buffer.observation_size();
+        buffer.push(
+            &bits(i, size),
+            i % 4,
+            i as f64,
+            0.5,
+            &bits(i + 1, size),
+            i % 5 == 4,
+        );
+    }
+
+    #[test]
+    fn replay_buffer_overwrites_the_oldest_and_iterates_in_order() {
+        let mut buffer = ReplayBuffer::new(4, 70);
+        for i in 0..3 {
+            push_step(&mut buffer, i);
+        }
+        let rewards = |buffer: &ReplayBuffer| buffer.iter().map(|t| t.reward).collect::<Vec<_>>();
+        assert_eq!(rewards(&buffer), [0.0, 1.0, 2.0]);
+
+        for i in 3..10 {
+            push_step(&mut buffer, i);
+        }
+        assert_eq!(buffer.len(), 4);
+        assert_eq!(rewards(&buffer), [6.0, 7.0, 8.0, 9.0]);
+        // Slot 1 held steps 1, 5 and now 9
+        let transition = buffer.get(1);
+        assert_eq!(transition.state, bits(9, 70));
+        assert_eq!(transition.next_state, bits(10, 70));
+        assert_eq!((transition.action, transition.done), (1, true));
+    }
+
+    #[test]
+    fn replay_buffer_switches_to_dense_storage_for_other_values() {
+        let mut buffer = ReplayBuffer::new(4, 3);
+        push_step(&mut buffer, 1);
+        push_step(&mut buffer, 2);
+        assert!(matches!(buffer.observations, Observations::Bits { .. }));
+
+        buffer.push(&[0.5, 1.0, -2.0], 3, -1.0, 0.9, &[0.0, 0.25, 1.0], true);
+        assert!(matches!(buffer.observations, Observations::Dense(_)));
+        let states: Vec<_> = buffer.iter().map(|t| t.state).collect();
+        assert_eq!(states, [bits(1, 3), bits(2, 3), vec![0.5, 1.0, -2.0]]);
+        assert_eq!(buffer.get(2).next_state, [0.0, 0.25, 1.0]);
+
+        // Binary observations are still stored exactly, and clearing the
+        // buffer goes back to packing them
+        push_step(&mut buffer, 3);
+        assert_eq!(buffer.get(3).state, bits(3, 3));
+        buffer.clear();
+        assert!(matches!(buffer.observations, Observations::Bits { .. }));
+    }
+
+    #[test]
+    fn replay_buffers_round_trip_in_either_encoding() {
+        let mut packed = ReplayBuffer::new(5, 70);
+        for i in 0..7 {
+            push_step(&mut packed, i);
+        }
+        let mut dense = packed.clone();
+        dense.push(&[0.5; 70], 2, 3.0, 0.9, &[-0.5; 70], false);
+
+        for buffer in [packed, dense] {
+            let mut bytes = Vec::new();
+            buffer.write_to(&mut bytes).unwrap();
+            let mut loaded = ReplayBuffer::read_from(&mut bytes.as_slice()).unwrap();
+            assert_eq!(
+                std::mem::discriminant(&loaded.observations),
+                std::mem::discriminant(&buffer.observations)
+            );
+            assert_eq!(
+                loaded.iter().collect::<Vec<_>>(),
+                buffer.iter().collect::<Vec<_>>()
+            );
+
+            // The next push goes into the same slot
+            let mut buffer = buffer;
+            push_step(&mut buffer, 20);
+            push_step(&mut loaded, 20);
+            assert_eq!(
+                loaded.iter().collect::<Vec<_>>(),
+                buffer.iter().collect::<Vec<_>>()
+            );
+        }
+    }
+
+    #[test]
+    fn sum_tree_samples_in_proportion_with_any_capacity() {
+        let mut tree = SumTree::new(5);
+        for (index, value) in [1.0, 2.0, 3.0, 4.0, 5.0].into_iter().enumerate() {
+            tree.update(index, value);
+        }
+        assert_eq!(tree.total(), 15.0);
+
+        // Midpoints of 15 equal slices land on every leaf as often as its value
+        let mut hits = [0; 5];
+        for i in 0..15 {
+            hits[tree.find(i as f64 + 0.5)] += 1;
+        }
+        assert_eq!(hits, [1, 2, 3, 4, 5]);
+
+        tree.update(2, 0.0);
+        assert_eq!(tree.total(), 12.0);
+        assert!((0..12).all(|i| tree.find(i as f64 + 0.5) != 2));
+    }
+
+    #[test]
+    fn sum_tree_never_walks_into_an_empty_subtree() {
+        let mut tree = SumTree::new(4);
+        tree.update(0, 1.0);
+        tree.update(1, 1.0);
+        // Rounding can ask for the very end of the range or just past it
+        assert_eq!(tree.find(2.0), 1);
+        assert_eq!(tree.find(2.0 + 1e-9), 1);
+    }
+
+    fn observation() -> Vec<f64> {
+        vec![0.0; 12]
+    }
+
+    #[test]
+    fn importance_weights_are_scaled_to_a_maximum_of_one() {
+        let config = PrioritizedConfig {
+            alpha: 1.0,
+            beta: 0.5,
+            beta_annealing_steps: u64::MAX,
+            epsilon: 0.0,
+        };
+        let mut memory = PrioritizedReplay::new(2, 12, config);
+        for _ in 0..2 {
+            memory.push(&observation(), 0, 0.0, 0.9, &observation(), false);
+        }
+        memory.update_priority(0, 1.0);
+        memory.update_priority(1, -3.0);
+
+        // P = (1/4, 3/4) so the raw weights are (2 * P)^-0.5, the rarer
+        // transition's being the largest
+        let sample = memory.sample(4, &mut rng::stream(1, 0));
+        assert_eq!(sample.indices, [0, 1, 1, 1]);
+        let expected = [1.0, (1.0f64 / 3.0).sqrt()];
+        for (&index, &weight) in sample.indices.iter().zip(&sample.weights) {
+            assert!((weight - expected[index]).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn beta_is_annealed_up_to_one() {
+        let config = PrioritizedConfig {
+            beta: 0.4,
+            beta_annealing_steps: 4,
+            ..PrioritizedConfig::default()
+        };
+        let mut memory = PrioritizedReplay::new(8, 12, config);
+        memory.push(&observation(), 1, 1.0, 0.9, &observation(), true);
+        let mut rng = rng::stream(1, 0);
+        let mut betas = Vec::new();
+        for _ in 0..6 {
+            memory.sample(2, &mut rng);
+            betas.push(memory.beta());
+        }
+        let expected = [0.55, 0.7, 0.85, 1.0, 1.0, 1.0];
+        assert!(betas
+            .iter()
+            .zip(expected)
+            .all(|(beta, x)| (beta - x).abs() < 1e-12));
+
+        memory.clear();
+        assert_eq!(memory.beta(), 1.0);
+    }
+
+    #[test]
+    fn implausible_stored_sizes_are_rejected() {
+        let memory = |capacity: u64, observation_size: u64| {
+            let mut bytes = Vec::new();
+            let mut memory = PrioritizedReplay::new(4, 12, PrioritizedConfig::default());
+            memory.write_to(&mut bytes).unwrap();
+            // The buffer header follows the magic, version and six settings
+            let header = 8 + 6 * 8;
+            bytes[header..header + 8].copy_from_slice(&capacity.to_le_bytes());
+            bytes[header + 8..header + 16].copy_from_slice(&observation_size.to_le_bytes());
+            memory
+                .read_from(&mut bytes.as_slice())
+                .map(|_| memory.capacity())
+        };
+        assert_eq!(memory(4, 12).unwrap(), 4);
+        for (capacity, observation_size) in
+            [(1 << 40, 12), (4, 1 << 40), (1 << 33, 1 << 33), (4, 0)]
+        {
+            let error = memory(capacity, observation_size).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn memories_that_skip_slots_are_rejected() {
+        let mut memory = PrioritizedReplay::new(4, 12, PrioritizedConfig::default());
+        for i in 0..2 {
+            memory.push(&bits(i, 12), 0, 1.0, 0.9, &bits(i + 1, 12), false);
+        }
+        let mut bytes = Vec::new();
+        memory.write_to(&mut bytes).unwrap();
+        // `next` follows the capacity and observation size
+        let next = 8 + 6 * 8 + 16;
+        assert_eq!(bytes[next..next + 8], 2u64.to_le_bytes());
+        bytes[next..next + 8].copy_from_slice(&3u64.to_le_bytes());
+        let error = memory.read_from(&mut bytes.as_slice()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn negative_or_non_finite_priorities_are_rejected() {
+        let mut memory = PrioritizedReplay::new(4, 12, PrioritizedConfig::default());
+        for i in 0..2 {
+            memory.push(&bits(i, 12), 0, 1.0, 0.9, &bits(i + 1, 12), false);
+        }
+        let mut bytes = Vec::new();
+        memory.write_to(&mut bytes).unwrap();
+        // The priorities come last, one per transition
+        let last = bytes.len() - 8;
+        for priority in [f64::NAN, f64::INFINITY, -1.0] {
+            let mut bytes = bytes.clone();
+            bytes[last..].copy_from_slice(&priority.to_le_bytes());
+            let error = memory.read_from(&mut bytes.as_slice()).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        }
+        memory.read_from(&mut bytes.as_slice()).unwrap();
+        assert_eq!(memory.len(), 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "cannot sample from an empty replay memory")]
+    fn sampling_an_empty_memory_panics() {
+        let mut memory = PrioritizedReplay::new(8, 12, PrioritizedConfig::default());
+        memory.sample(1, &mut rng::stream(1, 0));
+    }
+
+    fn stored(memory: &PrioritizedReplay) -> Vec<Transition> {
+        memory.buffer().iter().collect()
+    }
+
+    // A stored n-step transition with gamma 0.5 over `steps` steps
+    fn transition(
+        state: usize,
+        action: usize,
+        reward: f64,
+        steps: i32,
+        next: usize,
+        done: bool,
+    ) -> Transition {
+        Transition {
+            state: bits(state, 4),
+            action,
+            reward,
+            discount: 0.5f64.powi(steps),
+            next_state: bits(next, 4),
+            done,
+        }
+    }
+
+    #[test]
+    fn n_step_returns_discount_the_next_n_rewards() {
+        let mut memory = PrioritizedReplay::new(16, 4, PrioritizedConfig::default());
+        let mut accumulator = NStepAccumulator::new(3, 0.5);
+        for (i, reward) in [1.0, 2.0, 4.0, 8.0].into_iter().enumerate() {
+            accumulator.push(&bits(i, 4), i, reward, &bits(i + 1, 4), false, &mut memory);
+        }
+        assert_eq!(accumulator.len(), 2);
+        assert_eq!(
+            stored(&memory),
+            [
+                transition(0, 0, 1.0 + 0.5 * 2.0 + 0.25 * 4.0, 3, 3, false),
+                transition(1, 1, 2.0 + 0.5 * 4.0 + 0.25 * 8.0, 3, 4, false),
+            ]
+        );
+    }
+
+    #[test]
+    fn n_step_returns_stop_at_the_end_of_an_episode() {
+        let mut memory = PrioritizedReplay::new(16, 4, PrioritizedConfig::default());
+        let mut accumulator = NStepAccumulator::new(3, 0.5);
+        accumulator.push(&bits(0, 4), 0, 1.0, &bits(1, 4), false, &mut memory);
+        accumulator.push(&bits(1, 4), 1, 2.0, &bits(2, 4), true, &mut memory);
+        assert!(accumulator.is_empty());
+        // Every pending step ends on the terminal state, without bootstrapping
+        // and discounted for only the steps it got
+        assert_eq!(
+            stored(&memory),
+            [
+                transition(0, 0, 1.0 + 0.5 * 2.0, 2, 2, true),
+                transition(1, 1, 2.0, 1, 2, true),
+            ]
+        );
+
+        // and the next episode starts from scratch
+        accumulator.push(&bits(5, 4), 2, 4.0, &bits(6, 4), false, &mut memory);
+        accumulator.push(&bits(6, 4), 3, 8.0, &bits(7, 4), false, &mut memory);
+        accumulator.push(&bits(7, 4), 0, 0.0, &bits(8, 4), false, &mut memory);
+        assert_eq!(
+            stored(&memory)[2],
+            transition(5, 2, 4.0 + 0.5 * 8.0, 3, 8, false)
+        );
+    }
+}
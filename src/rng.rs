@@ -0,0 +1,45 @@
+use crate::persist;
+use rand::SeedableRng;
+use rand_chacha::ChaCha8Rng;
+use std::io::{self, Read, Write};
+
+// Every source of randomness (food placement, exploration, replay sampling,
+// weight init) draws from its own stream of one run seed, so two runs with the
+// same seed produce identical episodes and weights.
+pub type SimRng = ChaCha8Rng;
+
+pub const GAME_STREAM: u64 = 0;
+pub const AGENT_STREAM: u64 = 1;
+pub const NETWORK_STREAM: u64 = 2;
+
+pub fn stream(seed: u64, stream: u64) -> SimRng {
+    let mut rng = SimRng::seed_from_u64(seed);
+    rng.set_stream(stream);
+    rng
+}
+
+// Seed for runs that didn't ask for one
+pub fn random_seed() -> u64 {
+    rand::random()
+}
+
+// Exact generator position, so a resumed run draws the same numbers the
+// interrupted one would have
+pub fn write_state<W: Write>(w: &mut W, rng: &SimRng) -> io::Result<()> {
+    w.write_all(&rng.get_seed())?;
+    persist::write_u64(w, rng.get_stream())?;
+    w.write_all(&rng.get_word_pos().to_le_bytes())
+}
+
+pub fn read_state<R: Read>(r: &mut R) -> io::Result<SimRng> {
+    let mut seed = [0u8; 32];
+    r.read_exact(&mut seed)?;
+    let stream = persist::read_u64(r)?;
+    let mut word_pos = [0u8; 16];
+    r.read_exact(&mut word_pos)?;
+
+    let mut rng = SimRng::from_seed(seed);
+    rng.set_stream(stream);
+    rng.set_word_pos(u128::from_le_bytes(word_pos));
+    Ok(rng)
+}
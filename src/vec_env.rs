@@ -0,0 +1,190 @@
+use crate::agent::Agent;
+use crate::env::Environment;
+use crate::float::Float;
+use crate::parallel::EpisodeStats;
+
+// Batched result of stepping every environment once. `next_observations`
+// holds the observation straight after the step, so for finished episodes it
+// is the terminal one; the reset observation is available from
+// `VecEnv::observations`.
+pub struct VecStepOutcome<E> {
+    pub next_observations: Vec<Vec<f64>>,
+    pub rewards: Vec<f64>,
+    pub dones: Vec<bool>,
+    pub events: Vec<Vec<E>>,
+}
+
+// N independent environments stepped in lockstep. Finished episodes are
+// reset automatically so every slot always has a live episode.
+pub struct VecEnv<E: Environment> {
+    envs: Vec<E>,
+    observations: Vec<Vec<f64>>,
+}
+
+impl<E: Environment> VecEnv<E> {
+    pub fn new(mut envs: Vec<E>) -> Self {
+        assert!(!envs.is_empty(), "VecEnv needs at least one environment");
+        let observations = envs.iter_mut().map(|env| env.reset()).collect();
+        Self { envs, observations }
+    }
+
+    // Keep going with environments that are already mid-episode, e.g. ones
+    // restored from a checkpoint
+    pub fn resume(mut envs: Vec<E>) -> Self {
+        assert!(!envs.is_empty(), "VecEnv needs at least one environment");
+        let observations = envs.iter_mut().map(|env| env.observation()).collect();
+        Self { envs, observations }
+    }
+
+    pub fn len(&self) -> usize {
+        self.envs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.envs.is_empty()
+    }
+
+    pub fn envs(&self) -> &[E] {
+        &self.envs
+    }
+
+    pub fn observation_size(&self) -> usize {
+        self.envs[0].observation_size()
+    }
+
+    pub fn action_count(&self) -> usize {
+        self.envs[0].action_count()
+    }
+
+    // Current observation of every environment, one row per environment
+    pub fn observations(&self) -> &[Vec<f64>] {
+        &self.observations
+    }
+
+    pub fn reset(&mut self) -> &[Vec<f64>] {
+        for (env, observation) in self.envs.iter_mut().zip(&mut self.observations) {
+            *observation = env.reset();
+        }
+        &self.observations
+    }
+
+    pub fn seed(&mut self, seed: u64) {
+        for (i, env) in self.envs.iter_mut().enumerate() {
+            env.seed(seed.wrapping_add(i as u64));
+        }
+    }
+
+    pub fn step(&mut self, actions: &[usize]) -> VecStepOutcome<E::Event> {
+        assert_eq!(actions.len(), self.envs.len(), "one action per environment");
+
+        let mut outcome = VecStepOutcome {
+            next_observations: Vec::with_capacity(self.envs.len()),
+            rewards: Vec::with_capacity(self.envs.len()),
+            dones: Vec::with_capacity(self.envs.len()),
+            events: Vec::with_capacity(self.envs.len()),
+        };
+
+        for ((env, observation), &action) in self
+            .envs
+            .iter_mut()
+            .zip(&mut self.observations)
+            .zip(actions)
+        {
+            let step = env.step(action);
+            *observation = if step.done {
+                env.reset()
+            } else {
+                step.observation.clone()
+            };
+            outcome.next_observations.push(step.observation);
+            outcome.rewards.push(step.reward);
+            outcome.dones.push(step.done);
+            outcome.events.push(step.events);
+        }
+        outcome
+    }
+}
+
+// Lockstep self-play: `agent` acts in every environment at once, learns from
+// each transition and replays its memory whenever an episode ends, until it has
+// finished `episodes` episodes, counting those from before a resume. Episodes
+// are counted in `agent.episodes` just like `parallel::train` does.
+// `on_episode` sees each finished episode only once the step that ended it has
+// been learned from, so a checkpoint written from it resumes exactly.
+pub fn train<T, E, C>(
+    agent: &mut Agent<T>,
+    envs: &mut VecEnv<E>,
+    episodes: usize,
+    mut on_episode: C,
+) where
+    T: Float,
+    E: Environment,
+    E::Event: PartialEq,
+    C: FnMut(&EpisodeStats<E::Event>, &Agent<T>, &[E]),
+{
+    let mut stats: Vec<_> = (0..envs.len()).map(EpisodeStats::new).collect();
+
+    while agent.episodes < episodes {
+        let states = envs.observations().to_vec();
+        let actions = agent.select_actions(&states);
+        let outcome = envs.step(&actions);
+
+        let mut finished = Vec::new();
+        for (i, events) in outcome.events.into_iter().enumerate() {
+            let next_state = &outcome.next_observations[i];
+            let (reward, done) = (outcome.rewards[i], outcome.dones[i]);
+            agent.train(&states[i], actions[i], reward, next_state, done);
+            agent.remember_from(i, &states[i], actions[i], reward, next_state, done);
+
+            stats[i].total_reward += reward;
+            stats[i].steps += 1;
+            for event in events {
+                stats[i].record(event);
+            }
+            if done {
+                let mut episode = std::mem::replace(&mut stats[i], EpisodeStats::new(i));
+                episode.episode = agent.episodes;
+                agent.episodes += 1;
+                finished.push(episode);
+            }
+        }
+
+        if !finished.is_empty() {
+            agent.train_long_memory();
+            for mut episode in finished {
+                episode.epsilon = agent.epsilon;
+                on_episode(&episode, agent, envs.envs());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::{BoardConfig, Game};
+
+    #[test]
+    fn finished_episodes_report_the_terminal_observation_and_reset() {
+        let board = BoardConfig::new(6, 6);
+        let mut envs = VecEnv::new(vec![Game::with_seed(board, 3), Game::with_seed(board, 4)]);
+        let mut twin = Game::with_seed(board, 3);
+        twin.reset();
+
+        // Heading straight up always ends at the wall within a few steps
+        for _ in 0..10 {
+            let expected = Environment::step(&mut twin, 0);
+            let outcome = envs.step(&[0, 1]);
+            assert_eq!(outcome.next_observations[0], expected.observation);
+            assert_eq!(outcome.dones[0], expected.done);
+            if expected.done {
+                assert_eq!(envs.observations()[0], twin.reset());
+                assert_ne!(envs.observations()[0], outcome.next_observations[0]);
+                assert_eq!(envs.envs()[0].n_games, 1);
+                return;
+            }
+            assert_eq!(envs.observations()[0], outcome.next_observations[0]);
+        }
+        panic!("the snake never reached the wall");
+    }
+}